@@ -0,0 +1,183 @@
+// Parallel processing of bgzip compressed measurement files.
+//
+// A plain gzip file is one deflate stream that can only be decompressed front to back, which
+// throws away all the parallelism of v15. bgzip (BGZF, what `bgzip` from htslib writes) is a
+// series of independent gzip members of at most 64 KiB of input each, with the compressed size
+// of every block stored in a "BC" extra field. Walking those headers gives the block boundaries
+// without decompressing anything, so the blocks can be split between threads.
+//
+// Lines don't respect block boundaries, so every worker decompresses a contiguous run of blocks,
+// aggregates the complete lines in it and hands back the partial lines at either end. Those get
+// stitched together in order afterwards.
+
+use std::{io::{Error, ErrorKind, Read}, thread};
+
+use flate2::{Crc, read::DeflateDecoder};
+use memchr::memchr;
+
+use crate::v15::{BrcError, CustomHashMap, LineIter, format_output, merge_maps, parse_temp};
+
+// the empty block every BGZF file ends with
+const EOF_MARKER: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43,
+    0x02, 0x00, 0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+const HEADER_LEN: usize = 18;
+const MAX_BLOCK_INPUT: usize = 64 * 1024 - 1024;
+
+fn invalid(message: &str) -> BrcError {
+    return BrcError::Io(Error::new(ErrorKind::InvalidData, message.to_owned()));
+}
+
+pub fn run_bgzip(path: &str) -> Result<String, BrcError> {
+    let num_threads = thread::available_parallelism().map_or(4, |n| n.get());
+    return run_bgzip_with_threads(path, num_threads);
+}
+
+pub fn run_bgzip_with_threads(path: &str, num_threads: usize) -> Result<String, BrcError> {
+    let bytes = std::fs::read(path)?;
+    let blocks = find_blocks(&bytes)?;
+
+    let blocks_per_worker = blocks.len().div_ceil(num_threads.max(1)).max(1);
+    let results = thread::scope(|s| {
+        let handles: Vec<_> = blocks
+            .chunks(blocks_per_worker)
+            .map(|worker_blocks| {
+                let bytes = &bytes;
+                s.spawn(move || scan_blocks(bytes, worker_blocks))
+            })
+            .collect();
+        return handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .collect::<Result<Vec<_>, BrcError>>();
+    })?;
+
+    // stitch the partial lines between neighbouring workers back together
+    let mut maps = Vec::with_capacity(results.len() + 1);
+    let mut stitched = CustomHashMap::new();
+    let mut carry: Vec<u8> = Vec::new();
+    for part in results {
+        match part.tail {
+            Some(tail) => {
+                carry.extend_from_slice(&part.head);
+                process_line(&carry, &mut stitched);
+                carry = tail;
+            }
+            // no newline in this worker's blocks at all, the whole thing is the middle of a line
+            None => carry.extend_from_slice(&part.head),
+        }
+        maps.push(part.map);
+    }
+    if !carry.is_empty() {
+        process_line(&carry, &mut stitched);
+    }
+    maps.push(stitched);
+
+    return Ok(format_output(&merge_maps(maps)));
+}
+
+// (offset, length) of every data block, walking the BC extra fields. Errors unless the file is
+// BGZF and ends in the EOF marker
+pub(crate) fn find_blocks(bytes: &[u8]) -> Result<Vec<(usize, usize)>, BrcError> {
+    let mut blocks = vec![];
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let header = bytes.get(offset..offset + HEADER_LEN).ok_or_else(|| invalid("truncated bgzip block header"))?;
+        // gzip magic, deflate, FEXTRA set, XLEN 6, "BC" subfield of length 2
+        if header[..4] != [0x1f, 0x8b, 0x08, 0x04] || header[10..16] != [0x06, 0x00, b'B', b'C', 0x02, 0x00] {
+            return Err(invalid("not a bgzip file (gzip member without a BC extra field)"));
+        }
+        let block_len = u16::from_le_bytes([header[16], header[17]]) as usize + 1;
+        if offset + block_len > bytes.len() {
+            return Err(invalid("truncated bgzip block"));
+        }
+        if bytes[offset..offset + block_len] != EOF_MARKER {
+            blocks.push((offset, block_len));
+        } else if offset + block_len == bytes.len() {
+            return Ok(blocks);
+        }
+        offset += block_len;
+    }
+    return Err(invalid("bgzip file is missing its EOF marker, it was probably truncated"));
+}
+
+fn decompress_block(block: &[u8], out: &mut Vec<u8>) -> Result<(), BrcError> {
+    let trailer = &block[block.len() - 8..];
+    let expected_crc = u32::from_le_bytes(trailer[..4].try_into().unwrap());
+    let expected_len = u32::from_le_bytes(trailer[4..].try_into().unwrap()) as usize;
+
+    let start = out.len();
+    DeflateDecoder::new(&block[HEADER_LEN..block.len() - 8]).read_to_end(out)?;
+
+    let mut crc = Crc::new();
+    crc.update(&out[start..]);
+    if out.len() - start != expected_len || crc.sum() != expected_crc {
+        return Err(invalid("bgzip block failed its size or CRC check"));
+    }
+    return Ok(());
+}
+
+struct WorkerResult {
+    map: CustomHashMap,
+    // bytes before the first newline, the end of a line started in an earlier worker's blocks
+    head: Vec<u8>,
+    // bytes after the last newline, None if there was no newline at all
+    tail: Option<Vec<u8>>,
+}
+
+fn scan_blocks(bytes: &[u8], blocks: &[(usize, usize)]) -> Result<WorkerResult, BrcError> {
+    let mut data = Vec::with_capacity(blocks.len() * MAX_BLOCK_INPUT);
+    for (offset, len) in blocks {
+        decompress_block(&bytes[*offset..*offset + *len], &mut data)?;
+    }
+
+    let mut map = CustomHashMap::new();
+    let (Some(first_newline), Some(last_newline)) = (data.iter().position(|c| *c == b'\n'), data.iter().rposition(|c| *c == b'\n')) else {
+        return Ok(WorkerResult { map, head: data, tail: None });
+    };
+    for (name_slice, temp_slice) in LineIter::new(&data[first_newline + 1..last_newline + 1]) {
+        let temp = parse_temp(temp_slice);
+        map.get_mut(name_slice).add_temp(temp, name_slice);
+    }
+
+    return Ok(WorkerResult {
+        map,
+        head: data[..first_newline].to_vec(),
+        tail: Some(data[last_newline + 1..].to_vec()),
+    });
+}
+
+fn process_line(line: &[u8], map: &mut CustomHashMap) {
+    // no ; means no station (an empty carry, a truncated or garbage line), skip it like stream.rs
+    let Some(semicolon_pos) = memchr(b';', line) else {
+        return;
+    };
+    let name_slice = &line[..semicolon_pos];
+    let temp = parse_temp(&line[semicolon_pos + 1..]);
+    map.get_mut(name_slice).add_temp(temp, name_slice);
+}
+
+// writes data as BGZF with blocks of block_input bytes of input, for making fixtures
+pub(crate) fn compress_bgzip(data: &[u8], block_input: usize) -> Vec<u8> {
+    use std::io::Write;
+    use flate2::{Compression, write::DeflateEncoder};
+
+    let mut out = Vec::new();
+    for chunk in data.chunks(block_input.clamp(1, MAX_BLOCK_INPUT)) {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(chunk).unwrap();
+        let cdata = encoder.finish().unwrap();
+        let mut crc = Crc::new();
+        crc.update(chunk);
+
+        let block_len = HEADER_LEN + cdata.len() + 8;
+        out.extend_from_slice(&[0x1f, 0x8b, 0x08, 0x04, 0, 0, 0, 0, 0, 0xff, 0x06, 0x00, b'B', b'C', 0x02, 0x00]);
+        out.extend_from_slice(&((block_len - 1) as u16).to_le_bytes());
+        out.extend_from_slice(&cdata);
+        out.extend_from_slice(&crc.sum().to_le_bytes());
+        out.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+    }
+    out.extend_from_slice(&EOF_MARKER);
+    return out;
+}
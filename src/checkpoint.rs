@@ -0,0 +1,92 @@
+// Resumable single-reader processing for very large files.
+//
+// The file is scanned front to back in line aligned ranges on one thread, so "everything before
+// offset" is a meaningful amount of progress. Every checkpoint_every bytes the aggregates so far
+// and the offset are written to a checkpoint file, and resume picks up from there after a crash
+// or a kill instead of starting over.
+//
+// Layout: magic b"BRCK", u64 offset (little endian), then the map in serialize.rs's format
+
+use std::{fs::File, io::{Error, ErrorKind}, os::unix::fs::FileExt};
+
+use crate::{serialize::{deserialize_map, serialize_map}, v15::{BrcError, CustomHashMap, RunOptions, format_output, open_measurements, scan_file_segment_into}};
+
+const MAGIC: &[u8; 4] = b"BRCK";
+
+// processes the whole file from the start, checkpointing along the way. The checkpoint is removed
+// once the run completes
+pub fn run_with_checkpoints(measurements_path: &str, checkpoint_path: &str, checkpoint_every: usize) -> Result<String, BrcError> {
+    let map = process(measurements_path, checkpoint_path, checkpoint_every, CustomHashMap::new(), 0, usize::MAX)?;
+    std::fs::remove_file(checkpoint_path).ok();
+    return Ok(format_output(&map));
+}
+
+// continues a run from the offset stored in checkpoint_path
+pub fn resume(measurements_path: &str, checkpoint_path: &str, checkpoint_every: usize) -> Result<String, BrcError> {
+    let (map, offset) = read_checkpoint(checkpoint_path)?;
+    let map = process(measurements_path, checkpoint_path, checkpoint_every, map, offset, usize::MAX)?;
+    std::fs::remove_file(checkpoint_path).ok();
+    return Ok(format_output(&map));
+}
+
+// like run_with_checkpoints, but stops at the first line boundary at or past stop_at and leaves
+// the checkpoint in place, as if the run had been interrupted there
+pub(crate) fn process_until(measurements_path: &str, checkpoint_path: &str, checkpoint_every: usize, stop_at: usize) -> Result<(), BrcError> {
+    process(measurements_path, checkpoint_path, checkpoint_every, CustomHashMap::new(), 0, stop_at)?;
+    return Ok(());
+}
+
+fn process(measurements_path: &str, checkpoint_path: &str, checkpoint_every: usize, mut map: CustomHashMap, mut offset: usize, stop_at: usize) -> Result<CustomHashMap, BrcError> {
+    let file = open_measurements(measurements_path)?;
+    let file_len = file.metadata()?.len() as usize;
+    let options = RunOptions::default();
+
+    while offset < file_len && offset < stop_at {
+        let end = next_line_boundary(&file, offset.saturating_add(checkpoint_every).min(stop_at), file_len)?;
+        scan_file_segment_into(&mut map, &file, offset, end, &options)?;
+        offset = end;
+        write_checkpoint(checkpoint_path, &map, offset)?;
+    }
+    return Ok(map);
+}
+
+// the position just after the first \n at or after pos, or file_len if there isn't one
+fn next_line_boundary(file: &File, pos: usize, file_len: usize) -> Result<usize, BrcError> {
+    // longest valid line is 107 bytes, so a window this size always contains a \n
+    let mut buf = [0u8; 128];
+    let mut pos = pos;
+    while pos < file_len {
+        let bytes_read = file.read_at(&mut buf, pos as u64)?;
+        if let Some(i) = buf[..bytes_read].iter().position(|c| *c == b'\n') {
+            return Ok(pos + i + 1);
+        }
+        pos += bytes_read;
+    }
+    return Ok(file_len);
+}
+
+// written to a temporary file first and renamed over the old checkpoint, so a crash mid write
+// never leaves a torn checkpoint behind
+fn write_checkpoint(checkpoint_path: &str, map: &CustomHashMap, offset: usize) -> Result<(), BrcError> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&(offset as u64).to_le_bytes());
+    bytes.extend_from_slice(&serialize_map(map));
+
+    let tmp_path = format!("{}.tmp", checkpoint_path);
+    std::fs::write(&tmp_path, bytes)?;
+    std::fs::rename(&tmp_path, checkpoint_path)?;
+    return Ok(());
+}
+
+fn read_checkpoint(checkpoint_path: &str) -> Result<(CustomHashMap, usize), BrcError> {
+    let bytes = std::fs::read(checkpoint_path)?;
+    let malformed = || BrcError::Io(Error::new(ErrorKind::InvalidData, format!("\"{}\" is not a valid checkpoint", checkpoint_path)));
+
+    if bytes.len() < 12 || &bytes[..4] != MAGIC {
+        return Err(malformed());
+    }
+    let offset = u64::from_le_bytes(bytes[4..12].try_into().unwrap()) as usize;
+    let map = deserialize_map(&bytes[12..]).ok_or_else(malformed)?;
+    return Ok((map, offset));
+}
@@ -0,0 +1,133 @@
+// Command line parsing for the binary, by hand to keep the dependency list short.
+//
+//      one_brc_test [run] [--demo] [--repeat N] [--checksum]
+//      one_brc_test validate <measurements path>
+//      one_brc_test count <measurements path>
+//      one_brc_test bench <measurements path> [--runs N] [--version v15|v16|v17]
+//      one_brc_test verify <results path> [--expected <path>]
+//
+// Without a subcommand the arguments are run's flags, so `cargo run --release -- --demo` keeps
+// working as before.
+
+pub const USAGE: &str = "\
+usage:
+    one_brc_test [run] [--demo] [--repeat N] [--checksum]
+        run the challenge on measurements.txt (or the demo fixture) and check the result,
+        --checksum also prints a checksum of the output to compare runs by
+    one_brc_test validate <measurements path>
+        parse every line strictly and report the first malformed one
+    one_brc_test count <measurements path>
+        count the readings and stations in a file
+    one_brc_test bench <measurements path> [--runs N] [--version v15|v16|v17]
+        time N runs (default 5) of a version (default v16)
+    one_brc_test verify <results path> [--expected <path>]
+        check a results file is well formed and matches the expected output (default correct_results.txt)";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    V15,
+    V16,
+    V17,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Run { demo: bool, repeat: usize, checksum: bool },
+    Validate { path: String },
+    Count { path: String },
+    Bench { path: String, runs: usize, version: Version },
+    Verify { results_path: String, expected_path: Option<String> },
+    Help,
+}
+
+// turns argv (without the program name) into the command to run, errors are meant to be printed
+// next to USAGE
+pub fn parse_args(args: &[String]) -> Result<Command, String> {
+    let (subcommand, rest) = match args.first().map(String::as_str) {
+        None => ("run", args),
+        Some(first) if first.starts_with("--") && first != "--help" => ("run", args),
+        Some(first) => (first, &args[1..]),
+    };
+
+    let mut flags = Flags::new(subcommand, rest);
+    let command = match subcommand {
+        "run" => Command::Run {
+            demo: flags.switch("--demo"),
+            repeat: flags.number("--repeat")?.unwrap_or(1),
+            checksum: flags.switch("--checksum"),
+        },
+        "validate" => Command::Validate { path: flags.path("measurements path")? },
+        "count" => Command::Count { path: flags.path("measurements path")? },
+        "bench" => Command::Bench {
+            runs: flags.number("--runs")?.unwrap_or(5),
+            version: match flags.value("--version")?.as_deref() {
+                None | Some("v16") => Version::V16,
+                Some("v15") => Version::V15,
+                Some("v17") => Version::V17,
+                Some(other) => return Err(format!("unknown version \"{}\", expected v15, v16 or v17", other)),
+            },
+            path: flags.path("measurements path")?,
+        },
+        "verify" => Command::Verify {
+            expected_path: flags.value("--expected")?,
+            results_path: flags.path("results path")?,
+        },
+        "help" | "--help" => Command::Help,
+        other => return Err(format!("unknown subcommand \"{}\"", other)),
+    };
+    flags.finish()?;
+    return Ok(command);
+}
+
+// the arguments after the subcommand. Each flag is taken out as it's looked up, whatever is left
+// at the end is an error
+struct Flags<'a> {
+    subcommand: &'a str,
+    args: Vec<&'a str>,
+}
+
+impl<'a> Flags<'a> {
+    fn new(subcommand: &'a str, args: &'a [String]) -> Self {
+        Self { subcommand, args: args.iter().map(String::as_str).collect() }
+    }
+    fn switch(&mut self, name: &str) -> bool {
+        let Some(pos) = self.args.iter().position(|arg| *arg == name) else {
+            return false;
+        };
+        self.args.remove(pos);
+        return true;
+    }
+    fn value(&mut self, name: &str) -> Result<Option<String>, String> {
+        let Some(pos) = self.args.iter().position(|arg| *arg == name) else {
+            return Ok(None);
+        };
+        if pos + 1 >= self.args.len() {
+            return Err(format!("{} needs a value", name));
+        }
+        let value = self.args.remove(pos + 1).to_owned();
+        self.args.remove(pos);
+        return Ok(Some(value));
+    }
+    fn number(&mut self, name: &str) -> Result<Option<usize>, String> {
+        return match self.value(name)? {
+            None => Ok(None),
+            Some(value) => match value.parse() {
+                Ok(n) if n > 0 => Ok(Some(n)),
+                _ => Err(format!("{} must be a positive integer, got \"{}\"", name, value)),
+            },
+        };
+    }
+    // the positional argument, looked up after all the flags so a flag's value isn't taken for it
+    fn path(&mut self, what: &str) -> Result<String, String> {
+        let Some(pos) = self.args.iter().position(|arg| !arg.starts_with("--")) else {
+            return Err(format!("{} needs a {}", self.subcommand, what));
+        };
+        return Ok(self.args.remove(pos).to_owned());
+    }
+    fn finish(self) -> Result<(), String> {
+        return match self.args.first() {
+            None => Ok(()),
+            Some(arg) => Err(format!("unexpected argument \"{}\" for {}", arg, self.subcommand)),
+        };
+    }
+}
@@ -0,0 +1,144 @@
+// Correctness fuzzing for v15::run.
+//
+// Generates random but spec-valid measurement files (1 to 100 byte names, multibyte characters,
+// extreme temperatures, with and without a trailing newline) and compares v15::run against a
+// plain HashMap oracle. Like the helpers in misc.rs this is run by hand from main, e.g.
+// `fuzz::fuzz_v15(1_000);`, and prints the first failing case it finds. cargo test runs a short
+// fixed-seed round of it.
+
+use std::{collections::HashMap, panic};
+
+use crate::v15;
+
+// xorshift64*, good enough to generate test data without pulling in a dependency
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+    fn range(&mut self, lo: i64, hi: i64) -> i64 {
+        lo + (self.next() % (hi - lo + 1) as u64) as i64
+    }
+}
+
+fn random_name(rng: &mut Rng) -> String {
+    const CHARS: &[char] = &['a', 'b', 'k', 'z', 'A', 'Q', ' ', '-', '\'', '0', '7', 'é', 'ü', 'ß', 'ø'];
+
+    // pick a byte length between 1 and 100 first so both ends of the spec are hit regularly
+    let target_len = match rng.range(0, 9) {
+        0 => 1,
+        1 => 100,
+        _ => rng.range(1, 100) as usize,
+    };
+    // names made only of digits are valid too and must not be confused with a temperature
+    if rng.range(0, 19) == 0 {
+        return (0..target_len).map(|_| char::from(b'0' + rng.range(0, 9) as u8)).collect();
+    }
+
+    let mut name = String::new();
+    while name.len() < target_len {
+        let c = CHARS[rng.range(0, CHARS.len() as i64 - 1) as usize];
+        if name.len() + c.len_utf8() > target_len {
+            name.push('x');
+        } else {
+            name.push(c);
+        }
+    }
+    return name;
+}
+
+fn random_file(rng: &mut Rng) -> String {
+    let num_stations = rng.range(1, 50) as usize;
+    let names: Vec<_> = (0..num_stations).map(|_| random_name(rng)).collect();
+
+    let num_lines = rng.range(1, 5_000);
+    let mut contents = String::new();
+    for _ in 0..num_lines {
+        let name = &names[rng.range(0, num_stations as i64 - 1) as usize];
+        let temp = match rng.range(0, 9) {
+            0 => -999,
+            1 => 999,
+            _ => rng.range(-999, 999),
+        };
+        let sign = if temp < 0 { "-" } else { "" };
+        contents += &format!("{};{}{}.{}\n", name, sign, temp.abs() / 10, temp.abs() % 10);
+    }
+    if rng.range(0, 1) == 0 {
+        contents.pop();
+    }
+    return contents;
+}
+
+// independent implementation of the expected output
+fn oracle(contents: &str) -> String {
+    let mut map: HashMap<&str, (i64, i64, i64, u64)> = HashMap::new();
+    for line in contents.lines() {
+        let (name, temp) = line.split_once(';').unwrap();
+        let temp = (temp.parse::<f64>().unwrap() * 10.0).round() as i64;
+        let entry = map.entry(name).or_insert((i64::MAX, i64::MIN, 0, 0));
+        entry.0 = entry.0.min(temp);
+        entry.1 = entry.1.max(temp);
+        entry.2 += temp;
+        entry.3 += 1;
+    }
+
+    let mut parts = map
+        .iter()
+        .map(|(name, (min, max, total, count))| format!("{}={:.1}/{:.1}/{:.1}",
+            name,
+            0.1 * *min as f64,
+            // the reference's Math.round: half towards positive infinity, on the mean in tenths
+            0.1 * (*total as f64 / *count as f64 + 0.5).floor(),
+            0.1 * *max as f64
+        ))
+        .collect::<Vec<_>>();
+    parts.sort();
+
+    return "{".to_owned() + &parts.join(", ") + "}";
+}
+
+// returns true if all iterations matched the oracle
+pub fn fuzz_v15(iterations: usize) -> bool {
+    let path = std::env::temp_dir().join(format!("brc_fuzz_{}.txt", std::process::id()));
+    let path_str = path.to_str().unwrap().to_owned();
+    let mut rng = Rng(0x9e3779b97f4a7c15);
+
+    let mut passed = true;
+    for i in 0..iterations {
+        let contents = random_file(&mut rng);
+        std::fs::write(&path, &contents).unwrap();
+
+        let expected = oracle(&contents);
+        let actual = panic::catch_unwind(|| v15::run(&path_str));
+
+        let failure = match actual {
+            Ok(actual) if actual == expected => continue,
+            Ok(actual) => format!("output mismatch\nexpected: {}\ngot:      {}", expected, actual),
+            Err(_) => "v15::run panicked".to_owned(),
+        };
+
+        let failing_path = std::env::temp_dir().join(format!("brc_fuzz_failure_{}.txt", i));
+        std::fs::write(&failing_path, &contents).unwrap();
+        println!("FUZZ FAILURE on iteration {} (input saved to {:?}): {}", i, failing_path, failure);
+        passed = false;
+        break;
+    }
+
+    std::fs::remove_file(&path).ok();
+    if passed {
+        println!("FUZZ PASSED {} iterations", iterations);
+    }
+    return passed;
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn fuzz_v15_short() {
+        assert!(super::fuzz_v15(50));
+    }
+}
@@ -0,0 +1,106 @@
+// Per-station percentiles from fixed-width temperature histograms.
+//
+// Temperatures are whole tenths between -99.9 and 99.9, so 1,999 buckets (one per tenth) give
+// exact percentiles. That's ~8 KB per station though, ~80 MB at 10,000 stations, so the bucket
+// count can be lowered to trade precision for memory: with n buckets each one covers
+// ceil(1999 / n) tenths and a percentile is reported as the middle of its bucket (clamped to the
+// station's min/max), so it can be off by up to half a bucket. At 200 buckets that's 10 tenths
+// per bucket, 800 bytes per station and an error of at most 0.5 degrees.
+//
+// Unlike the t-digest in sketch.rs the error bound is fixed and known up front, but the range
+// is tied to the spec's temperature range; readings outside it land in the edge buckets.
+
+use crate::v15::run_per_station;
+
+const MIN_TEMP: i32 = -999;
+const MAX_TEMP: i32 = 999;
+pub const FULL_RESOLUTION_BUCKETS: usize = (MAX_TEMP - MIN_TEMP + 1) as usize;
+
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    buckets: Vec<u32>,
+    // tenths covered by each bucket
+    bucket_width: i32,
+    count: u64,
+    min_temp: i32,
+    max_temp: i32,
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Self::with_buckets(FULL_RESOLUTION_BUCKETS)
+    }
+    pub fn with_buckets(num_buckets: usize) -> Self {
+        let num_buckets = num_buckets.clamp(1, FULL_RESOLUTION_BUCKETS);
+        let bucket_width = FULL_RESOLUTION_BUCKETS.div_ceil(num_buckets) as i32;
+        Self {
+            // the last bucket may be cut short by the rounding up of the width
+            buckets: vec![0; FULL_RESOLUTION_BUCKETS.div_ceil(bucket_width as usize)],
+            bucket_width,
+            count: 0,
+            min_temp: i32::MAX,
+            max_temp: i32::MIN,
+        }
+    }
+    pub fn num_buckets(&self) -> usize {
+        self.buckets.len()
+    }
+    #[inline(always)]
+    pub fn add(&mut self, temp: i32) {
+        let index = (temp.clamp(MIN_TEMP, MAX_TEMP) - MIN_TEMP) / self.bucket_width;
+        self.buckets[index as usize] += 1;
+        self.count += 1;
+        self.min_temp = self.min_temp.min(temp);
+        self.max_temp = self.max_temp.max(temp);
+    }
+    // both histograms need the same bucket count
+    pub fn merge_with(&mut self, other: &Histogram) {
+        assert_eq!(self.buckets.len(), other.buckets.len(), "can't merge histograms with different bucket counts");
+        for (bucket, other_bucket) in self.buckets.iter_mut().zip(&other.buckets) {
+            *bucket += other_bucket;
+        }
+        self.count += other.count;
+        self.min_temp = self.min_temp.min(other.min_temp);
+        self.max_temp = self.max_temp.max(other.max_temp);
+    }
+
+    // nearest-rank p-th percentile (0.0 ..= 1.0) in tenths, exact at full resolution and the
+    // middle of the bucket otherwise (except for 0.0 and 1.0, which are the exact min and max)
+    pub fn percentile(&self, p: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        // the extremes are tracked exactly, no need to go through the buckets
+        if p <= 0.0 {
+            return Some(self.min_temp as f64);
+        }
+        if p >= 1.0 {
+            return Some(self.max_temp as f64);
+        }
+        let rank = ((p * self.count as f64).ceil() as u64).max(1);
+
+        let mut cum = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            cum += *bucket as u64;
+            if cum >= rank {
+                let bucket_start = MIN_TEMP + i as i32 * self.bucket_width;
+                let bucket_end = (bucket_start + self.bucket_width - 1).min(MAX_TEMP);
+                let middle = (bucket_start + bucket_end) as f64 / 2.0;
+                return Some(middle.clamp(self.min_temp as f64, self.max_temp as f64));
+            }
+        }
+        unreachable!("bucket counts add up to count");
+    }
+}
+
+// scans the file in parallel and reports the requested percentiles of each station, formatted
+// like the normal output but with one value per percentile: {name=p0/p1/...}
+pub fn run_percentiles(measurements_path: &str, percentiles: &[f64], num_buckets: usize) -> String {
+    return run_per_station(
+        measurements_path,
+        || Histogram::with_buckets(num_buckets),
+        |histogram, temp| histogram.add(temp),
+        |merged, histogram| merged.merge_with(histogram),
+        |histogram| percentiles.iter().map(|p| format!("{:.1}", 0.1 * histogram.percentile(*p).unwrap())).collect::<Vec<_>>().join("/"),
+    );
+}
@@ -28,13 +28,38 @@
 // - Running my code:
 //      - Run as normal: `cargo run --release`
 //
+//      - Without the billion row file: `cargo run --release -- --demo` runs on the small committed
+//        fixture in fixtures/ and checks it against the expected output next to it
+//
+//      - Other subcommands (validate, count, bench, verify), see cli.rs or
+//        `cargo run --release -- help`
+//
 //      - Profiling:
 //          - `cargo build --profile profiling`
 //          - `samply record ./target/profiling/one_brc_test`
+//
+//      - Built-in stage timings (printed to stderr for every v15 run):
+//          - `cargo run --release --features profiling`
+//
+//      - Heap profiling with dhat:
+//          - `cargo run --release --features dhat-heap`
 
 #![feature(portable_simd)]
 
+#[cfg(feature = "bgzip")]
+mod bgzip;
+mod checkpoint;
+mod cli;
+mod fuzz;
+mod histogram;
 mod misc;
+mod mmap;
+mod serialize;
+#[cfg(all(unix, feature = "shm"))]
+mod shm;
+mod sketch;
+mod stream;
+mod summary;
 mod v1;
 mod v2;
 mod v3;
@@ -49,8 +74,12 @@ mod v11;
 mod v12;
 mod v13;
 mod v14;
+mod v14_par;
 mod v15;
 mod v16;
+mod v17;
+mod verify;
+mod window;
 
 use std::time::Instant;
 
@@ -58,6 +87,8 @@ use regex::Regex;
 
 const MEASUREMENTS_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/measurements.txt");
 const CORRECT_RESULTS_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/correct_results.txt");
+const DEMO_MEASUREMENTS_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/demo_measurements.txt");
+const DEMO_RESULTS_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/demo_results.txt");
 
 #[cfg(feature = "dhat-heap")]
 #[global_allocator]
@@ -67,34 +98,195 @@ fn main() {
     #[cfg(feature = "dhat-heap")]
     let _profiler = dhat::Profiler::new_heap();
 
-    let start = Instant::now();
-
     // misc::store_city_names();
     // misc::test_hash_function();
     // misc::find_seed();
     // misc::test_read_speed(4);
+    // misc::test_mean_precision();
+    // misc::test_thread_budget();
+    // misc::test_ascii_hint();
+    // misc::test_diff_stations();
+    // misc::test_temp_first();
+    // misc::test_find_char_agreement();
+    // misc::bench_find_char(256 * 1024 * 1024);
+    // misc::test_snap_to_char_boundary();
+    // misc::test_scan_line();
+    // misc::test_accumulate_into();
+    // misc::test_detect_format();
+    // misc::test_segment_merge();
+    // misc::test_temp_overflow();
+    // misc::test_zero_count_station();
+    // misc::test_carry_guard();
+    // misc::test_multi_format();
+    // misc::test_adaptive_segments();
+    // misc::test_from_samples();
+    // misc::test_v16_metrics();
+    // misc::test_checkpoint_resume();
+    // misc::test_name_length();
+    // misc::test_occupied_merge();
+    // misc::test_decimal_comma();
+    // misc::test_streamed_merge();
+    // misc::test_summary_stats();
+    // misc::test_profiling_run();
+    // misc::test_bgzip();
+    // misc::test_near_duplicates();
+    // misc::test_columnar();
+    // misc::test_chunk_boundary_line();
+    // misc::test_run_config();
+    // misc::test_mmap_fallback();
+    // misc::test_kway_merge();
+    // misc::test_seed_collisions();
+    // misc::test_windowed();
+    // misc::test_maps_equal();
+    // misc::test_split_separator();
+    // misc::test_unsorted_output();
+    // misc::test_more_segments_than_lines();
+    // misc::test_input_unit();
+    // misc::test_query_station();
+    // misc::test_shared_file_reads();
+    // misc::test_histogram_buckets();
+    // misc::test_demo_fixture();
+    // misc::test_interim_snapshots();
+    // misc::test_find_char_padded();
+    // misc::test_display_name();
+    // misc::test_low_memory();
+    // misc::test_zero_total_mean();
+    // misc::test_allowlist();
+    // misc::test_exact_mean_formatting();
+    // misc::test_open_precheck();
+    // misc::test_shm_output();
+    // misc::test_collapse_runs();
+    // misc::test_sorted_input();
+    // misc::test_grouped_output();
+    // misc::test_natural_sort();
+    // misc::test_v16_worker_idle();
+    // misc::test_merge_by_name();
+    // misc::test_cli_dispatch();
+    // misc::test_missing_integer_digit();
+    // misc::test_output_checksum();
+    // misc::test_wide_total();
+    // misc::test_colliding_names();
+    // misc::test_trailing_cr();
+    // misc::test_rotated();
+    // misc::test_round_half_up();
+    // misc::test_span();
+    // misc::test_v17();
+    // misc::test_mean_tie_table();
+    // misc::test_precomputed_splits();
+    // misc::test_long_line_at_split();
+    // misc::test_key_sampling();
+    // misc::test_multi_read_segment();
+    // misc::test_streamed_output();
+    // misc::test_v14_par_splits();
+    // misc::test_merge_serialized();
+    // misc::test_stream_malformed();
+    // misc::test_stream_read_error();
+    // fuzz::fuzz_v15(1_000);
     // return;
 
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let command = cli::parse_args(&args).unwrap_or_else(|e| {
+        eprintln!("{}\n\n{}", e, cli::USAGE);
+        std::process::exit(2);
+    });
+
+    match command {
+        cli::Command::Run { demo, repeat, checksum } => run_challenge(demo, repeat, checksum),
+        cli::Command::Validate { path } => validate(&path),
+        cli::Command::Count { path } => count(&path),
+        cli::Command::Bench { path, runs, version } => bench(&path, runs, version),
+        cli::Command::Verify { results_path, expected_path } => verify_results(&results_path, expected_path.as_deref().unwrap_or(CORRECT_RESULTS_PATH)),
+        cli::Command::Help => println!("{}", cli::USAGE),
+    }
+}
+
+fn run_challenge(demo: bool, repeat: usize, checksum: bool) {
+    let start = Instant::now();
+
     // run the 1brc code
-    let results = v16::run(MEASUREMENTS_PATH);
+    let (measurements_path, correct_path) = if demo {
+        (DEMO_MEASUREMENTS_PATH, DEMO_RESULTS_PATH)
+    } else {
+        (MEASUREMENTS_PATH, CORRECT_RESULTS_PATH)
+    };
+    let results = run_repeated(measurements_path, repeat, v16::run);
 
     println!("Run completed in: {:?} seconds", start.elapsed().as_secs_f32());
+    if checksum {
+        println!("Output checksum: {:016x}", verify::output_checksum(&results));
+    }
 
     // store results
     store_result(&results);
 
     // check the result
-    check_correct(&results);
+    check_correct(&results, correct_path);
 }
 
+fn validate(measurements_path: &str) {
+    let options = v15::RunOptions { strict: true, ..Default::default() };
+    match v15::run_with_options(measurements_path, &options) {
+        Ok(_) => println!("{} is valid", measurements_path),
+        Err(e) => {
+            println!("{} is invalid: {}", measurements_path, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn count(measurements_path: &str) {
+    let (map, _stats) = v15::aggregate_file(measurements_path, &v15::RunOptions::default())
+        .unwrap_or_else(|e| panic!("{}", e));
+    let readings: u64 = map.stations().map(|data| data.count as u64).sum();
+    println!("{} readings from {} stations", readings, map.stations().count());
+}
+
+fn bench(measurements_path: &str, runs: usize, version: cli::Version) {
+    let run: fn(&str) -> String = match version {
+        cli::Version::V15 => v15::run,
+        cli::Version::V16 => v16::run,
+        cli::Version::V17 => v17::run,
+    };
+    let mut times = Vec::with_capacity(runs);
+    for i in 0..runs {
+        let start = Instant::now();
+        run(measurements_path);
+        times.push(start.elapsed().as_secs_f32());
+        println!("run {}: {:.3}s", i + 1, times[i]);
+    }
+    let best = times.iter().cloned().fold(f32::INFINITY, f32::min);
+    println!("{:?} over {} runs: best {:.3}s, mean {:.3}s", version, runs, best, times.iter().sum::<f32>() / runs as f32);
+}
+
+fn verify_results(results_path: &str, expected_path: &str) {
+    let results = std::fs::read_to_string(results_path).unwrap();
+    if let Err(e) = verify::check_output_wellformed(&results) {
+        println!("{} is not well formed: {}", results_path, e);
+        std::process::exit(1);
+    }
+    check_correct(&results, expected_path);
+}
+
+// runs the pipeline `repeat` times, panicking on the first run whose output differs from the
+// first one. Catches nondeterminism from the parallel merge or from state leaking between runs
+fn run_repeated(measurements_path: &str, repeat: usize, run: fn(&str) -> String) -> String {
+    let first = run(measurements_path);
+    for i in 1..repeat {
+        let next = run(measurements_path);
+        if next != first {
+            panic!("run {} of {} produced different output than the first run", i + 1, repeat);
+        }
+    }
+    return first;
+}
 
 fn store_result(results: &str) {
     std::fs::write("my_results.txt", results).unwrap();
     println!("Results stored in \"my_results.txt\"");
 }
 
-fn check_correct(results: &str) {
-    let correct = std::fs::read_to_string(CORRECT_RESULTS_PATH).unwrap();
+fn check_correct(results: &str, correct_path: &str) {
+    let correct = std::fs::read_to_string(correct_path).unwrap();
 
     if results != correct {
         println!("ERROR, output does not match expected!");
@@ -1,9 +1,40 @@
-use std::{collections::HashMap, fs::File, os::unix::fs::FileExt, thread, time::Instant};
+use std::{collections::HashMap, fs::File, ops::Deref, os::unix::fs::FileExt, path::{Path, PathBuf}, sync::atomic::{AtomicUsize, Ordering}, thread, time::Instant};
 
 
 use regex::Regex;
 
-use crate::{CORRECT_RESULTS_PATH, MEASUREMENTS_PATH};
+use crate::{CORRECT_RESULTS_PATH, DEMO_MEASUREMENTS_PATH, DEMO_RESULTS_PATH, MEASUREMENTS_PATH, v15, verify};
+
+static TEMP_PATH_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// a temp file path no other check uses, even with checks running in parallel under cargo test:
+// brc_<pid>_<counter>_<name>. Whatever gets written there is removed when it's dropped, so a
+// failing check doesn't leave its fixture behind either
+struct TempPath(PathBuf);
+
+fn temp_path(name: &str) -> TempPath {
+    let id = TEMP_PATH_COUNTER.fetch_add(1, Ordering::Relaxed);
+    return TempPath(std::env::temp_dir().join(format!("brc_{}_{}_{}", std::process::id(), id, name)));
+}
+
+impl Deref for TempPath {
+    type Target = Path;
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.0).ok();
+    }
+}
 
 pub fn store_city_names() {
     let correct = std::fs::read_to_string(CORRECT_RESULTS_PATH).unwrap();
@@ -174,4 +205,2094 @@ pub fn test_read_speed(num_threads: usize) {
     
     println!("TOTAL_BYTES_READ: {}", total_bytes_read);
     println!("TIME_ELAPSED: {}", start_time.elapsed().as_secs_f32())
-}
\ No newline at end of file
+}
+// a total/count pair where the mean computed in f32 rounds to the wrong tenth. The true mean is
+// 0.149999995 so the correct output is 0.1, f32 can't tell it apart from 0.15 and prints 0.2
+pub fn test_mean_precision() {
+    let mut data = v15::StationData::new();
+    data.total = 29_999_999;
+    data.count = 20_000_000;
+    data.min_temp = -999;
+    data.max_temp = 999;
+    data.name = Some(b"Precision".to_vec());
+
+    let f32_mean = format!("{:.1}", 0.1 * data.total as f32 / data.count as f32);
+    println!("f32 mean: {}", f32_mean);
+    println!("format_data_point: {}", data.format_data_point());
+    assert_eq!(data.format_data_point(), "Precision=-99.9/0.1/99.9");
+}
+
+// runs 4 files through run_many's shared pool with a budget of 4 threads and checks the result
+// against aggregating each file separately, and that only 4 workers were spawned instead of
+// a thread for every segment of every file
+pub fn test_thread_budget() {
+    let paths = [MEASUREMENTS_PATH; 4];
+    let options = v15::RunOptions::default();
+
+    let (pooled, stats) = v15::aggregate_files(&paths, 4, &options).unwrap();
+    println!("workers spawned: {}", stats.worker_threads);
+    assert_eq!(stats.worker_threads, 4);
+
+    let separate = paths
+        .iter()
+        .map(|path| v15::aggregate_file(path, &options).unwrap().0)
+        .collect();
+    assert_eq!(v15::format_output(&pooled), v15::format_output(&v15::merge_maps(separate)));
+    println!("thread budget check passed");
+}
+
+// detect_ascii on a pure ASCII fixture (hint set) and on one with a multibyte name (hint clear),
+// both giving the same output as without the hint
+pub fn test_ascii_hint() {
+    let ascii_path = temp_path("ascii_fixture.txt");
+    let multibyte_path = temp_path("multibyte_fixture.txt");
+    std::fs::write(&ascii_path, "Hamburg;12.0\nBulawayo;8.9\nPalembang;38.8\n".repeat(1000)).unwrap();
+    std::fs::write(&multibyte_path, "Hamburg;12.0\nAbéché;8.9\nPalembang;38.8\n".repeat(1000)).unwrap();
+
+    assert!(v15::detect_ascii(&File::open(&ascii_path).unwrap()));
+    assert!(!v15::detect_ascii(&File::open(&multibyte_path).unwrap()));
+
+    assert_eq!(v15::run(ascii_path.to_str().unwrap()), "{Bulawayo=8.9/8.9/8.9, Hamburg=12.0/12.0/12.0, Palembang=38.8/38.8/38.8}");
+    assert_eq!(v15::run(multibyte_path.to_str().unwrap()), "{Abéché=8.9/8.9/8.9, Hamburg=12.0/12.0/12.0, Palembang=38.8/38.8/38.8}");
+    println!("ascii hint check passed");
+}
+
+// one output is missing a station (and has one the other doesn't), both should land in the
+// right bucket
+pub fn test_diff_stations() {
+    let a = "{Abha=-1.0/2.0/5.0, Hamburg=1.0/2.0/3.0, São Paulo, Brazil=0.0/0.5/1.0}";
+    let b = "{Hamburg=1.0/2.0/3.0, São Paulo, Brazil=0.0/0.5/1.0, Zürich=0.1/0.1/0.1}";
+
+    let (only_in_a, only_in_b) = verify::diff_stations(a, b);
+    assert_eq!(only_in_a, vec!["Abha".to_owned()]);
+    assert_eq!(only_in_b, vec!["Zürich".to_owned()]);
+    assert_eq!(verify::diff_stations(a, a), (vec![], vec![]));
+    println!("diff_stations check passed");
+}
+
+// the same measurements written as temp;name should aggregate to the same result with
+// FieldOrder::TempFirst, in both lenient and strict mode
+pub fn test_temp_first() {
+    let path = temp_path("temp_first_fixture.txt");
+    std::fs::write(&path, "12.0;Hamburg\n-8.9;Bulawayo\n38.8;Palembang\n-0.5;Hamburg\n".repeat(1000)).unwrap();
+    let path_str = path.to_str().unwrap();
+
+    let mut options = v15::RunOptions { field_order: v15::FieldOrder::TempFirst, ..Default::default() };
+    let expected = "{Bulawayo=-8.9/-8.9/-8.9, Hamburg=-0.5/5.8/12.0, Palembang=38.8/38.8/38.8}";
+    assert_eq!(v15::run_with_options(path_str, &options).unwrap(), expected);
+    options.strict = true;
+    assert_eq!(v15::run_with_options(path_str, &options).unwrap(), expected);
+    println!("temp first check passed");
+}
+
+// positions of every ; and \n in buf, found line by line with the given search function the
+// same way the scanners do it (; first, then the newline after it)
+fn delimiter_positions(buf: &[u8], find: fn(&[u8], u8) -> Option<usize>) -> Vec<usize> {
+    let mut positions = Vec::new();
+    let mut line_start = 0;
+    while let Some(semicolon_pos) = find(&buf[line_start..], b';') {
+        let semicolon_pos = line_start + semicolon_pos;
+        let Some(newline_pos) = find(&buf[semicolon_pos + 1..], b'\n') else {
+            break;
+        };
+        let newline_pos = semicolon_pos + 1 + newline_pos;
+        positions.push(semicolon_pos);
+        positions.push(newline_pos);
+        line_start = newline_pos + 1;
+    }
+    return positions;
+}
+
+fn memchr_find(buf: &[u8], target: u8) -> Option<usize> {
+    return memchr::memchr(target, buf);
+}
+
+// reads the first sample_size bytes of the measurements file into memory
+fn read_sample(sample_size: usize) -> Vec<u8> {
+    let file = File::open(MEASUREMENTS_PATH).unwrap();
+    let mut buf = vec![0u8; sample_size];
+    let bytes_read = file.read_at(&mut buf, 0).unwrap();
+    buf.truncate(bytes_read);
+    return buf;
+}
+
+// times memchr-only scanning against the u8x16 find_char (with its memchr fallback) over the
+// start of the real file, to tune find_char's buf.len() >= 48 threshold with evidence. The
+// crossover depends on the average line length, so that's printed too
+pub fn bench_find_char(sample_size: usize) {
+    let buf = read_sample(sample_size);
+    let num_lines = buf.iter().filter(|c| **c == b'\n').count();
+    println!("sample: {} bytes, {} lines, {:.1} bytes per line", buf.len(), num_lines, buf.len() as f32 / num_lines as f32);
+
+    let start_time = Instant::now();
+    let memchr_positions = delimiter_positions(&buf, memchr_find);
+    let memchr_time = start_time.elapsed();
+
+    let start_time = Instant::now();
+    let simd_positions = delimiter_positions(&buf, v15::find_char_unbounded);
+    let simd_time = start_time.elapsed();
+
+    assert_eq!(memchr_positions.len(), simd_positions.len());
+    println!("memchr: {:?}", memchr_time);
+    println!("simd:   {:?}", simd_time);
+    println!("{} wins", if simd_time < memchr_time { "simd" } else { "memchr" });
+}
+
+// both scanners have to find exactly the same delimiters, on the demo fixture and on generated
+// lines with 1 to 100 byte names, so the ; lands before, inside and past find_char's 48 byte window
+pub fn test_find_char_agreement() {
+    let mut buf = std::fs::read(DEMO_MEASUREMENTS_PATH).unwrap();
+    for len in 1..=100 {
+        let name = "é".repeat(len / 2) + &"x".repeat(len % 2);
+        buf.extend_from_slice(format!("{};{}.{}\n", name, len as i32 - 50, len % 10).as_bytes());
+    }
+    assert_eq!(delimiter_positions(&buf, memchr_find), delimiter_positions(&buf, v15::find_char_unbounded));
+    println!("find_char agreement check passed");
+}
+
+// snapping every offset of a buffer full of multibyte names has to leave both halves valid
+// UTF-8, and the segment splits of a file made of them have to be character boundaries already
+pub fn test_snap_to_char_boundary() {
+    let contents = "Abéché;12.0\nÜrümqi;-3.4\nİzmir;0.0\nZürich;9.9\n".repeat(10_000);
+    let bytes = contents.as_bytes();
+    for offset in 0..200 {
+        let snapped = v15::snap_to_char_boundary(bytes, offset);
+        assert!(snapped >= offset);
+        assert!(contents.is_char_boundary(snapped));
+    }
+    assert_eq!(v15::snap_to_char_boundary(bytes, bytes.len()), bytes.len());
+
+    let path = temp_path("multibyte_boundaries.txt");
+    std::fs::write(&path, &contents).unwrap();
+    for (start, end) in v15::find_segment_splits(&File::open(&path).unwrap(), v15::NUM_SEGMENTS) {
+        assert!(contents.is_char_boundary(start) && contents.is_char_boundary(end));
+        assert_eq!(v15::snap_to_char_boundary(bytes, start), start);
+    }
+    println!("snap_to_char_boundary check passed");
+}
+
+// walks scan_line over a buffer of several lines, checking every tuple and that an unterminated
+// last line isn't returned
+pub fn test_scan_line() {
+    let buf = b"Hamburg;12.0\nBulawayo;-8.9\nSt. John's;0.0\nAbha;-0.1\nPalembang;38.8";
+    let expected: [(&[u8], i32, usize); 4] = [
+        (b"Hamburg", 120, 13),
+        (b"Bulawayo", -89, 27),
+        (b"St. John's", 0, 42),
+        (b"Abha", -1, 52),
+    ];
+
+    let mut offset = 0;
+    for (name, temp, next_offset) in expected {
+        assert_eq!(v15::scan_line(buf, offset), Some((name, temp, next_offset)));
+        offset = next_offset;
+    }
+    assert_eq!(v15::scan_line(buf, offset), None);
+    println!("scan_line check passed");
+}
+
+// accumulating two files into one map has to match a single scan over both files' contents
+pub fn test_accumulate_into() {
+    let first = "Hamburg;12.0\nBulawayo;8.9\nAbéché;-3.1\n".repeat(1000);
+    let second = "Hamburg;-4.0\nPalembang;38.8\nAbéché;41.2\n".repeat(700);
+    let (first_path, second_path, combined_path) = (temp_path("acc_1.txt"), temp_path("acc_2.txt"), temp_path("acc_both.txt"));
+    std::fs::write(&first_path, &first).unwrap();
+    std::fs::write(&second_path, &second).unwrap();
+    std::fs::write(&combined_path, first + &second).unwrap();
+
+    let mut map = v15::CustomHashMap::new();
+    v15::accumulate_into(first_path.to_str().unwrap(), &mut map, 2);
+    v15::accumulate_into(second_path.to_str().unwrap(), &mut map, 2);
+    assert_eq!(v15::format_output(&map), v15::run(combined_path.to_str().unwrap()));
+
+    println!("accumulate_into check passed");
+}
+
+// detect_format on a one decimal and a two decimal fixture, plus one mixing both which has to
+// error, and run_auto formatting the two decimal one with two decimals
+pub fn test_detect_format() {
+    let (one_path, two_path, mixed_path) = (temp_path("one_decimal.txt"), temp_path("two_decimals.txt"), temp_path("mixed_decimals.txt"));
+    std::fs::write(&one_path, "Hamburg;12.0\nBulawayo;-8.9\n".repeat(500)).unwrap();
+    std::fs::write(&two_path, "Hamburg;12.05\nPalembang;38.80\n".repeat(500)).unwrap();
+    std::fs::write(&mixed_path, "Hamburg;12.0\nPalembang;38.80\n".repeat(500)).unwrap();
+
+    let one = v15::detect_format(one_path.to_str().unwrap(), 1000).unwrap();
+    assert_eq!(one, v15::FormatProfile { fractional_digits: 1, has_negatives: true });
+    let two = v15::detect_format(two_path.to_str().unwrap(), 1000).unwrap();
+    assert_eq!(two, v15::FormatProfile { fractional_digits: 2, has_negatives: false });
+    let mixed = v15::detect_format(mixed_path.to_str().unwrap(), 1000);
+    assert!(matches!(mixed, Err(v15::BrcError::MixedResolution { expected: 1, found: 2, .. })));
+    println!("{}", mixed.unwrap_err());
+
+    assert_eq!(v15::run_auto(two_path.to_str().unwrap()).unwrap(), "{Hamburg=12.05/12.05/12.05, Palembang=38.80/38.80/38.80}");
+
+    println!("detect_format check passed");
+}
+
+// scans two adjacent segments and merges them, which has to give the same result as scanning the
+// whole range as one segment. Pins down that no line at the boundary is dropped or counted twice
+pub fn test_segment_merge() {
+    let file_len = File::open(MEASUREMENTS_PATH).unwrap().metadata().unwrap().len();
+    let splits = v15::find_segment_splits(&File::open(MEASUREMENTS_PATH).unwrap(), 2);
+    let boundary = splits[0].1 as u64;
+
+    let first = v15::scan_segment(MEASUREMENTS_PATH, 0, boundary);
+    let second = v15::scan_segment(MEASUREMENTS_PATH, boundary, file_len);
+    let whole = v15::scan_segment(MEASUREMENTS_PATH, 0, file_len);
+
+    assert_eq!(v15::format_output(&v15::merge_maps(vec![first, second])), v15::format_output(&whole));
+    println!("segment merge check passed");
+}
+
+// an over-long numeric temp has to be a strict mode error and saturate in lenient mode instead
+// of silently wrapping around
+pub fn test_temp_overflow() {
+    let temp = b"12345678901.2";
+    assert_eq!(v15::parse_temp(temp), i32::MAX);
+    assert_eq!(v15::parse_temp(b"-12345678901.2"), -i32::MAX);
+    assert_eq!(v15::scan_line(b"Hamburg;12345678901.2\n", 0), Some((&b"Hamburg"[..], i32::MAX, 22)));
+
+    let error = v15::parse_temp_strict(b"Hamburg", temp).unwrap_err();
+    assert!(matches!(error, v15::BrcError::MalformedLine { reason: "too many integer digits", .. }));
+    println!("{}", error);
+    println!("temp overflow check passed");
+}
+
+// a station with a name but zero readings must not be formatted with a NaN mean, and is left out
+// of the output entirely
+pub fn test_zero_count_station() {
+    let mut data = v15::StationData::new();
+    data.name = Some(b"Hamburg".to_vec());
+    assert_eq!(data.format_data_point(), "Hamburg=n/a");
+
+    let mut map = v15::CustomHashMap::new();
+    map.get_mut(b"Hamburg").name = Some(b"Hamburg".to_vec());
+    map.get_mut(b"Palembang").add_temp(388, b"Palembang");
+    let output = v15::format_output(&map);
+    assert!(!output.contains("NaN"));
+    assert_eq!(output, "{Palembang=38.8/38.8/38.8}");
+    println!("zero count station check passed");
+}
+
+// with a deliberately tiny buffer normal lines still get stitched together through carry, but a
+// line longer than any valid one has to trigger the carry guard instead of growing carry forever
+pub fn test_carry_guard() {
+    let (short_path, long_path) = (temp_path("carry_short.txt"), temp_path("carry_long.txt"));
+    std::fs::write(&short_path, "Hamburg;12.0\nBulawayo;-8.9\nPalembang;38.8\n").unwrap();
+    std::fs::write(&long_path, format!("Hamburg;12.0\n{};1.0\n", "x".repeat(200))).unwrap();
+
+    let short = crate::v14::run_with_buffer_size(short_path.to_str().unwrap(), 8).unwrap();
+    assert_eq!(short, "{Bulawayo=-8.9/-8.9/-8.9, Hamburg=12.0/12.0/12.0, Palembang=38.8/38.8/38.8}");
+
+    let error = crate::v14::run_with_buffer_size(long_path.to_str().unwrap(), 16).unwrap_err();
+    assert!(matches!(error, v15::BrcError::LineTooLong { carried, max_line_len: 107, buffer_size: 16 } if carried > 107));
+    println!("{}", error);
+    println!("carry guard check passed");
+}
+
+// one scan rendered as both Braced and Json, each checked against the expected text
+pub fn test_multi_format() {
+    let path = temp_path("multi_format.txt");
+    std::fs::write(&path, "Hamburg;12.0\nSt. \"Q\" Town;-8.9\nAbéché;38.8\nHamburg;-0.5\n".repeat(500)).unwrap();
+    let path_str = path.to_str().unwrap();
+
+    let outputs = v15::run_multi_format(path_str, &[v15::Format::Braced, v15::Format::Json]);
+    assert_eq!(outputs.len(), 2);
+    assert_eq!(outputs[0], (v15::Format::Braced, "{Abéché=38.8/38.8/38.8, Hamburg=-0.5/5.8/12.0, St. \"Q\" Town=-8.9/-8.9/-8.9}".to_owned()));
+    assert_eq!(outputs[0].1, v15::run(path_str));
+    assert_eq!(outputs[1], (v15::Format::Json, concat!(
+        "{\"Abéché\":{\"min\":38.8,\"mean\":38.8,\"max\":38.8},",
+        "\"Hamburg\":{\"min\":-0.5,\"mean\":5.8,\"max\":12.0},",
+        "\"St. \\\"Q\\\" Town\":{\"min\":-8.9,\"mean\":-8.9,\"max\":-8.9}}"
+    ).to_owned()));
+    println!("multi format check passed");
+}
+
+// generated files of several sizes, up to one past the segment cap, checking the segment count
+// chosen for each and that the parallel run matches a single segment scan
+pub fn test_adaptive_segments() {
+    const PATTERN: &str = "Hamburg;12.0\nBulawayo;-8.9\nPalembang;38.8\nZürich;-0.5\n";
+    let path = temp_path("adaptive_segments.txt");
+    let path_str = path.to_str().unwrap();
+    let cores = thread::available_parallelism().unwrap().get();
+    let cap = (2 * cores).min(v15::MAX_SEGMENTS);
+
+    let sizes = [(1024, 1), (3 * 1024 * 1024, 1), (10 * 1024 * 1024, 3.min(cap)), ((cap + 1) * v15::BYTES_PER_SEGMENT, cap)];
+    for (size, expected_segments) in sizes {
+        // whole pattern repeats, cut at the last line boundary within size
+        let mut contents = PATTERN.repeat(size / PATTERN.len() + 1).into_bytes();
+        let end = contents[..size].iter().rposition(|c| *c == b'\n').unwrap() + 1;
+        contents.truncate(end);
+        std::fs::write(&path, &contents).unwrap();
+
+        let num_segments = v15::choose_num_segments(end);
+        assert_eq!(num_segments, expected_segments, "{} bytes", end);
+
+        let whole = v15::scan_segment(path_str, 0, end as u64);
+        assert_eq!(whole.stations().count(), 4);
+        assert_eq!(v15::run(path_str), v15::format_output(&whole));
+    }
+    println!("adaptive segments check passed");
+}
+
+// two stations built from samples and merged have to give the combined min/mean/max
+pub fn test_from_samples() {
+    let mut a = v15::StationData::from_samples(b"Hamburg", &[120, -35, 42]);
+    let b = v15::StationData::from_samples(b"Hamburg", &[-99, 301]);
+    assert_eq!(a.format_data_point(), "Hamburg=-3.5/4.2/12.0");
+
+    a.merge_from(b);
+    assert_eq!((a.min_temp, a.max_temp, a.total, a.count), (-99, 301, 329, 5));
+    assert_eq!(a.format_data_point(), "Hamburg=-9.9/6.6/30.1");
+    println!("from_samples check passed");
+}
+
+// v16 over a file bigger than one 16 MiB buffer has to report a nonzero full_chunks depth, and
+// never more than the number of buffers
+pub fn test_v16_metrics() {
+    let (result, metrics) = crate::v16::run_with_metrics(MEASUREMENTS_PATH);
+    println!("max full_chunks depth: {}", metrics.max_full_chunks_depth);
+    assert!((1..=8).contains(&metrics.max_full_chunks_depth));
+    assert_eq!(result, crate::v16::run(MEASUREMENTS_PATH));
+    println!("v16 metrics check passed");
+}
+
+// processing half the file, stopping there with a checkpoint and resuming from it has to give the
+// same result as a full run
+pub fn test_checkpoint_resume() {
+    let checkpoint_path = temp_path("checkpoint.bin");
+    let checkpoint_str = checkpoint_path.to_str().unwrap();
+    let file_len = File::open(MEASUREMENTS_PATH).unwrap().metadata().unwrap().len() as usize;
+    let checkpoint_every = 1024 * 1024;
+
+    crate::checkpoint::process_until(MEASUREMENTS_PATH, checkpoint_str, checkpoint_every, file_len / 2).unwrap();
+    assert!(checkpoint_path.exists());
+    let resumed = crate::checkpoint::resume(MEASUREMENTS_PATH, checkpoint_str, checkpoint_every).unwrap();
+    assert!(!checkpoint_path.exists());
+
+    assert_eq!(resumed, v15::run(MEASUREMENTS_PATH));
+    assert_eq!(crate::checkpoint::run_with_checkpoints(MEASUREMENTS_PATH, checkpoint_str, checkpoint_every).unwrap(), resumed);
+    println!("checkpoint resume check passed");
+}
+
+// two shards serialized separately and merged have to match a run over both, and a truncated part
+// has to come back as an error naming it rather than a panic
+pub fn test_merge_serialized() {
+    let first_path = temp_path("shard_1.txt");
+    let second_path = temp_path("shard_2.txt");
+    let both_path = temp_path("shards.txt");
+    std::fs::write(&first_path, "Hamburg;12.0\nAbha;-3.5\n").unwrap();
+    std::fs::write(&second_path, "Hamburg;-1.0\nZürich;4.2\n").unwrap();
+    std::fs::write(&both_path, "Hamburg;12.0\nAbha;-3.5\nHamburg;-1.0\nZürich;4.2\n").unwrap();
+
+    let first = crate::serialize::serialize_file(first_path.to_str().unwrap()).unwrap();
+    let second = crate::serialize::serialize_file(second_path.to_str().unwrap()).unwrap();
+    assert_eq!(crate::serialize::merge_serialized(&[&first, &second]).unwrap(), v15::run(both_path.to_str().unwrap()));
+
+    let truncated = crate::serialize::merge_serialized(&[&first, &second[..second.len() - 1]]).unwrap_err();
+    assert_eq!(truncated.to_string(), "io error: serialized part 1 is malformed");
+
+    // the last 8 bytes are the last station's count, one that doesn't fit a u32 is malformed too
+    let mut overflowing = second.clone();
+    let count_pos = overflowing.len() - 8;
+    overflowing[count_pos..].copy_from_slice(&(u32::MAX as u64 + 1).to_le_bytes());
+    let overflowed = crate::serialize::merge_serialized(&[&first, &overflowing]).unwrap_err();
+    assert_eq!(overflowed.to_string(), "io error: serialized part 1 is malformed");
+
+    println!("merge serialized check passed");
+}
+
+// lines without a ; (blank or garbage) are skipped by the streaming scan instead of panicking it,
+// and run_tcp reports a bad address as an error and otherwise aggregates what a client sends
+pub fn test_stream_malformed() {
+    use std::io::Write;
+
+    let input = "Hamburg;12.0\n\nno semicolon\nAbha;-3.5\n\n";
+    assert_eq!(crate::stream::run_stream(input.as_bytes()).unwrap(), "{Abha=-3.5/-3.5/-3.5, Hamburg=12.0/12.0/12.0}");
+
+    assert!(matches!(crate::stream::run_tcp("not an address"), Err(v15::BrcError::Io(_))));
+
+    // grab a free port, then hand it to run_tcp and connect once it's listening
+    let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let server = thread::spawn(move || crate::stream::run_tcp(&addr.to_string()));
+    let mut client = loop {
+        match std::net::TcpStream::connect(addr) {
+            Ok(client) => break client,
+            Err(_) => thread::sleep(std::time::Duration::from_millis(10)),
+        }
+    };
+    client.write_all(input.as_bytes()).unwrap();
+    drop(client);
+    assert_eq!(server.join().unwrap().unwrap(), "{Abha=-3.5/-3.5/-3.5, Hamburg=12.0/12.0/12.0}");
+    println!("stream malformed check passed");
+}
+
+// hands out data 7 bytes at a time, is interrupted once after the first read, and once fail_at
+// bytes have gone out fails like a reset connection
+struct FlakyReader {
+    data: Vec<u8>,
+    pos: usize,
+    fail_at: Option<usize>,
+    interrupted: bool,
+}
+
+impl std::io::Read for FlakyReader {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos > 0 && !self.interrupted {
+            self.interrupted = true;
+            return Err(std::io::ErrorKind::Interrupted.into());
+        }
+        let limit = self.fail_at.unwrap_or(self.data.len());
+        if self.pos >= limit && self.fail_at.is_some() {
+            return Err(std::io::ErrorKind::ConnectionReset.into());
+        }
+        let n = out.len().min(7).min(limit - self.pos);
+        out[..n].copy_from_slice(&self.data[self.pos..self.pos + n]);
+        self.pos += n;
+        return Ok(n);
+    }
+}
+
+// an interrupted read is retried, a failing one comes back as an error instead of a panic, with
+// both the \n and the separator scan
+pub fn test_stream_read_error() {
+    let input = "Hamburg;12.0\nAbha;-3.5\nHamburg;2.0\n".repeat(10);
+    let reader = |fail_at| FlakyReader { data: input.clone().into_bytes(), pos: 0, fail_at, interrupted: false };
+
+    assert_eq!(crate::stream::run_stream(reader(None)).unwrap(), "{Abha=-3.5/-3.5/-3.5, Hamburg=2.0/7.0/12.0}");
+    assert_eq!(crate::stream::run_stream_with_separator(reader(None), b"\n").unwrap(), "{Abha=-3.5/-3.5/-3.5, Hamburg=2.0/7.0/12.0}");
+
+    let reset = |result| matches!(result, Err(v15::BrcError::Io(e)) if e.kind() == std::io::ErrorKind::ConnectionReset);
+    assert!(reset(crate::stream::run_stream(reader(Some(40)))));
+    assert!(reset(crate::stream::run_stream_with_separator(reader(Some(40)), b"\n")));
+    println!("stream read error check passed");
+}
+
+// strict mode has to reject a 0 byte and a 101 byte name and accept a 100 byte one (and a 1 byte
+// one), lenient mode counts the two bad ones as warnings
+pub fn test_name_length() {
+    let long_name = "x".repeat(101);
+    let max_name = "y".repeat(100);
+
+    let empty = v15::parse_temp_strict(b"", b"1.0").unwrap_err();
+    assert!(matches!(empty, v15::BrcError::MalformedLine { reason: "empty station name", .. }));
+    let too_long = v15::parse_temp_strict(long_name.as_bytes(), b"1.0").unwrap_err();
+    assert!(matches!(too_long, v15::BrcError::MalformedLine { reason: "station name longer than 100 bytes", .. }));
+    println!("{}", too_long);
+    assert_eq!(v15::parse_temp_strict(max_name.as_bytes(), b"1.0").unwrap(), 10);
+    assert_eq!(v15::parse_temp_strict(b"Q", b"-2.5").unwrap(), -25);
+
+    let path = temp_path("name_length.txt");
+    std::fs::write(&path, format!(";1.0\n{};2.0\n{};3.0\nQ;4.0\n", long_name, max_name)).unwrap();
+    let (result, stats) = v15::run_with_stats(path.to_str().unwrap()).unwrap();
+    assert_eq!(stats.name_length_warnings, 2);
+    assert!(result.contains(&format!("{}=3.0/3.0/3.0", max_name)) && result.contains("Q=4.0/4.0/4.0"));
+    let strict = v15::RunOptions { strict: true, ..Default::default() };
+    assert!(v15::run_with_options(path.to_str().unwrap(), &strict).is_err());
+    println!("name length check passed");
+}
+
+// the occupied slot list of every segment map (and of the merged map) has to match the slots
+// that actually hold a station, and the merge that only walks those slots has to give the same
+// result as a single scan
+pub fn test_occupied_merge() {
+    let file_len = File::open(MEASUREMENTS_PATH).unwrap().metadata().unwrap().len();
+    let splits = v15::find_segment_splits(&File::open(MEASUREMENTS_PATH).unwrap(), 3);
+    let non_empty_slots = |map: &v15::CustomHashMap| map
+        .backing()
+        .iter()
+        .enumerate()
+        .filter(|(_, data)| data.count > 0)
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+
+    let maps: Vec<_> = splits
+        .iter()
+        .map(|(start, end)| v15::scan_segment(MEASUREMENTS_PATH, *start as u64, *end as u64))
+        .collect();
+    for map in &maps {
+        assert_eq!(map.occupied_slots(), non_empty_slots(map));
+    }
+
+    let merged = v15::merge_maps(maps);
+    assert_eq!(merged.occupied_slots(), non_empty_slots(&merged));
+    assert_eq!(v15::format_output(&merged), v15::format_output(&v15::scan_segment(MEASUREMENTS_PATH, 0, file_len)));
+    println!("occupied merge check passed");
+}
+
+// a comma decimal file has to come out with the same magnitudes as its '.' version, in lenient
+// mode and in strict mode with decimal_sep set to ','
+pub fn test_decimal_comma() {
+    let path = temp_path("decimal_comma.txt");
+    std::fs::write(&path, "Hamburg;12,3\nBulawayo;-8,9\nHamburg;-0,5\n".repeat(1000)).unwrap();
+    let path_str = path.to_str().unwrap();
+    let expected = "{Bulawayo=-8.9/-8.9/-8.9, Hamburg=-0.5/5.9/12.3}";
+
+    assert_eq!(v15::run(path_str), expected);
+    let comma = v15::RunOptions { strict: true, decimal_sep: b',', ..Default::default() };
+    assert_eq!(v15::run_with_options(path_str, &comma).unwrap(), expected);
+    let point = v15::RunOptions { strict: true, ..Default::default() };
+    assert!(v15::run_with_options(path_str, &point).is_err());
+    assert_eq!(v15::parse_temp_strict_with(b"Hamburg", b"12,3", b',').unwrap(), 123);
+    println!("decimal comma check passed");
+}
+
+// merging maps as the workers finish has to give the same result as joining them all first
+pub fn test_streamed_merge() {
+    assert_eq!(v15::run_streamed_merge(MEASUREMENTS_PATH), v15::run(MEASUREMENTS_PATH));
+    println!("streamed merge check passed");
+}
+
+// a fixture with known per-station means: Hamburg 5.0, Bulawayo -2.0, Palembang 30.0, Abha 5.0
+pub fn test_summary_stats() {
+    let mut map = v15::CustomHashMap::new();
+    for (name, temps) in [(&b"Hamburg"[..], &[40, 60][..]), (b"Bulawayo", &[-20]), (b"Palembang", &[300, 250, 350]), (b"Abha", &[50])] {
+        map.get_mut(name).merge_from(v15::StationData::from_samples(name, temps));
+    }
+
+    let summary = crate::summary::summary_stats(&map);
+    assert_eq!(summary.coldest_mean, Some(("Bulawayo".to_owned(), -2.0)));
+    assert_eq!(summary.warmest_mean, Some(("Palembang".to_owned(), 30.0)));
+    assert!((summary.average_of_means.unwrap() - 9.5).abs() < 1e-9);
+    assert_eq!(summary.num_stations, 4);
+
+    let empty = crate::summary::summary_stats(&v15::CustomHashMap::new());
+    assert_eq!((empty.coldest_mean, empty.average_of_means), (None, None));
+    println!("summary stats check passed");
+}
+
+// meant to be run with `--features profiling`: the instrumented run prints its stage timings to
+// stderr and still has to return the same output as a plain single segment scan
+pub fn test_profiling_run() {
+    let file_len = File::open(MEASUREMENTS_PATH).unwrap().metadata().unwrap().len();
+    let (result, stats) = v15::run_with_stats(MEASUREMENTS_PATH).unwrap();
+    assert_eq!(result, v15::format_output(&v15::scan_segment(MEASUREMENTS_PATH, 0, file_len)));
+    assert!(stats.total_time() > std::time::Duration::ZERO);
+    println!("profiling run check passed (profiling feature {})", if cfg!(feature = "profiling") { "on" } else { "off" });
+}
+
+// compresses ~4 MB of the demo fixture repeated into a bgzip fixture with small blocks, so lines
+// straddle blocks and workers, and checks the parallel result against the uncompressed run. Also
+// checks that a truncated file (no EOF marker) is an error and that stitched lines without a ; are
+// skipped. Needs `--features bgzip`
+#[cfg(feature = "bgzip")]
+pub fn test_bgzip() {
+    let contents = std::fs::read(DEMO_MEASUREMENTS_PATH).unwrap().repeat(6_000);
+    let end = contents.iter().rposition(|c| *c == b'\n').unwrap() + 1;
+    let (plain_path, gz_path) = (temp_path("bgzip_plain.txt"), temp_path("bgzip_fixture.txt.gz"));
+    std::fs::write(&plain_path, &contents[..end]).unwrap();
+    let compressed = crate::bgzip::compress_bgzip(&contents[..end], 10_000);
+    std::fs::write(&gz_path, &compressed).unwrap();
+
+    let expected = v15::run(plain_path.to_str().unwrap());
+    for num_threads in [1, 3, 8] {
+        assert_eq!(crate::bgzip::run_bgzip_with_threads(gz_path.to_str().unwrap(), num_threads).unwrap(), expected);
+    }
+
+    std::fs::write(&gz_path, &compressed[..compressed.len() - 28]).unwrap();
+    assert!(crate::bgzip::run_bgzip(gz_path.to_str().unwrap()).is_err());
+
+    // a line without a ; straddling the 10,000 byte block (and worker) boundary, and one at the
+    // very end without a newline, are both left over for stitching and have to be skipped
+    let garbage = "Hamburg;12.0\n".repeat(769) + "garbage\n" + &"Abha;-3.5\n".repeat(100) + "trailing";
+    assert!(garbage.find("garbage").unwrap() < 10_000 && garbage.find("garbage\n").unwrap() + 8 > 10_000);
+    std::fs::write(&gz_path, crate::bgzip::compress_bgzip(garbage.as_bytes(), 10_000)).unwrap();
+    assert_eq!(crate::bgzip::run_bgzip_with_threads(gz_path.to_str().unwrap(), 2).unwrap(), "{Abha=-3.5/-3.5/-3.5, Hamburg=12.0/12.0/12.0}");
+    println!("bgzip check passed");
+}
+
+// "Tokyo", "Tokyo " and "tokyo" have to end up in one group and " Oslo"/"OSLO" in another, while
+// distinct stations aren't reported
+pub fn test_near_duplicates() {
+    let path = temp_path("near_duplicates.txt");
+    std::fs::write(&path, "Tokyo;12.0\nTokyo ;13.0\ntokyo;1.0\n Oslo;-3.0\nOSLO;2.0\nHamburg;5.0\nHamburg;6.0\nKyoto;7.0\n".repeat(100)).unwrap();
+
+    let groups = crate::summary::report_near_duplicates(path.to_str().unwrap()).unwrap();
+    assert_eq!(groups, vec![
+        vec![" Oslo".to_owned(), "OSLO".to_owned()],
+        vec!["Tokyo".to_owned(), "Tokyo ".to_owned(), "tokyo".to_owned()],
+    ]);
+    println!("near duplicates check passed");
+}
+
+// rebuilds the text output from the columnar form, which has to match format_output exactly
+pub fn test_columnar() {
+    let map = v15::scan_segment(MEASUREMENTS_PATH, 0, File::open(MEASUREMENTS_PATH).unwrap().metadata().unwrap().len());
+    let columnar = crate::summary::results_columnar(&crate::summary::station_summaries(&map));
+    assert!(columnar.counts.len() == columnar.names.len() && columnar.counts.iter().all(|count| *count > 0));
+
+    // format_output rounds the mean half towards positive on the integers while the columns keep
+    // the exact f64 mean, so the mean is compared by value and everything else as text
+    let mut parts: Vec<_> = (0..columnar.names.len())
+        .map(|i| (format!("{}={:.1}/", columnar.names[i], columnar.mins[i]), columnar.means[i], format!("/{:.1}", columnar.maxes[i])))
+        .collect();
+    // format_output sorts the formatted entries rather than the names, which differs when one
+    // name is a prefix of another, so do the same here
+    parts.sort_by(|a, b| a.0.cmp(&b.0));
+
+    // names can contain ", " so the output is walked entry by entry instead of split
+    let output = v15::format_output(&map);
+    let mut rest = &output[1..];
+    for (head, mean, tail) in &parts {
+        rest = rest.strip_prefix(head.as_str()).unwrap_or_else(|| panic!("expected {} at {}", head, rest));
+        let mean_len = rest.find('/').unwrap();
+        assert!((rest[..mean_len].parse::<f64>().unwrap() - mean).abs() <= 0.05 + 1e-9, "{}{} vs {}", head, &rest[..mean_len], mean);
+        rest = rest[mean_len..].strip_prefix(tail.as_str()).unwrap();
+        rest = rest.strip_prefix(", ").unwrap_or(rest);
+    }
+    assert_eq!(rest, "}");
+    println!("columnar check passed");
+}
+
+// a fixture with one line that straddles the end of the first SCAN_BUF_SIZE chunk of a single
+// segment, which has to be picked up by re-reading from its start rather than dropped or split
+pub fn test_chunk_boundary_line() {
+    const FILLER: &str = "Hamburg;1.0\n";
+    let path = temp_path("chunk_boundary.txt");
+    let mut contents = FILLER.repeat(v15::SCAN_BUF_SIZE / FILLER.len());
+    let straddle_start = contents.len();
+    contents += "Straddle;12.3\n";
+    assert!(straddle_start < v15::SCAN_BUF_SIZE && contents.len() > v15::SCAN_BUF_SIZE);
+    contents += &FILLER.repeat(1000);
+    std::fs::write(&path, &contents).unwrap();
+
+    let map = v15::scan_segment(path.to_str().unwrap(), 0, contents.len() as u64);
+    let expected = crate::stream::run_stream(File::open(&path).unwrap()).unwrap();
+    assert_eq!(v15::format_output(&map), expected);
+    assert_eq!(expected, "{Hamburg=1.0/1.0/1.0, Straddle=12.3/12.3/12.3}");
+    println!("chunk boundary line check passed");
+}
+
+// a config with several options set, each of which has to show up in the result
+pub fn test_run_config() {
+    let (path, malformed_path) = (temp_path("run_config.txt"), temp_path("run_config_malformed.txt"));
+    std::fs::write(&path, "12,3;Hamburg\n-8,9;Bulawayo\n-0,5;Hamburg\n".repeat(1000)).unwrap();
+    std::fs::write(&malformed_path, "12,3;Hamburg\n12,34;Bulawayo\n").unwrap();
+    let path_str = path.to_str().unwrap();
+
+    let config = v15::RunConfig::new()
+        .threads(2)
+        .strict(true)
+        .field_order(v15::FieldOrder::TempFirst)
+        .decimal_sep(b',')
+        .format(v15::Format::Json);
+    let (result, stats) = v15::run_config_with_stats(path_str, &config).unwrap();
+    assert_eq!(result, "{\"Bulawayo\":{\"min\":-8.9,\"mean\":-8.9,\"max\":-8.9},\"Hamburg\":{\"min\":-0.5,\"mean\":5.9,\"max\":12.3}}");
+    assert!(stats.worker_threads <= 2);
+
+    let by_mean = config.clone().format(v15::Format::Braced).sort_by(v15::SortKey::Mean);
+    assert_eq!(v15::run_config(path_str, &by_mean).unwrap(), "{Bulawayo=-8.9/-8.9/-8.9, Hamburg=-0.5/5.9/12.3}");
+    // strict mode rejects the two fractional digits, lenient mode takes them
+    assert!(v15::run_config(malformed_path.to_str().unwrap(), &config).is_err());
+    assert!(v15::run_config(malformed_path.to_str().unwrap(), &config.strict(false)).is_ok());
+    println!("run config check passed");
+}
+
+// a zero-length file has to give "{}" without mapping anything, and the positioned read fallback
+// used when mapping fails has to agree with the mapped run
+pub fn test_mmap_fallback() {
+    let empty_path = temp_path("mmap_empty.txt");
+    std::fs::write(&empty_path, "").unwrap();
+    assert_eq!(crate::mmap::run_mmap(empty_path.to_str().unwrap()).unwrap(), "{}");
+    assert_eq!(crate::mmap::run_mmap_or_fallback(empty_path.to_str().unwrap(), false).unwrap(), "{}");
+
+    let mapped = crate::mmap::run_mmap(MEASUREMENTS_PATH).unwrap();
+    let fallback = crate::mmap::run_mmap_or_fallback(MEASUREMENTS_PATH, false).unwrap();
+    assert_eq!(mapped, fallback);
+    assert_eq!(mapped, v15::run(MEASUREMENTS_PATH));
+    println!("mmap fallback check passed");
+}
+
+// several pre-sorted lists, some sharing stations, have to come out in global name order with
+// the shared stations combined
+pub fn test_kway_merge() {
+    let from = |name: &str, temps: &[i32]| v15::StationData::from_samples(name.as_bytes(), temps);
+    let lists = vec![
+        vec![from("Abha", &[10]), from("Hamburg", &[20]), from("Zürich", &[30])],
+        vec![from("Bulawayo", &[-40]), from("Hamburg", &[60, -10])],
+        vec![],
+        vec![from("Abha", &[50]), from("Palembang", &[388]), from("Yakutsk", &[-500])],
+    ];
+
+    let merged = v15::kway_merge_sorted(lists);
+    let names: Vec<_> = merged.iter().map(|data| String::from_utf8(data.name.clone().unwrap()).unwrap()).collect();
+    assert_eq!(names, ["Abha", "Bulawayo", "Hamburg", "Palembang", "Yakutsk", "Zürich"]);
+    assert_eq!(merged[0].format_data_point(), "Abha=1.0/3.0/5.0");
+    assert_eq!(merged[2].format_data_point(), "Hamburg=-1.0/2.3/6.0");
+    assert_eq!(merged.iter().map(|data| data.count).sum::<u32>(), 9);
+    println!("kway merge check passed");
+}
+
+const CITY_NAMES_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/city_names.txt");
+
+// pairs of stations from city_names.txt that land in the same slot of v11-v14's 12_289 slot table
+// under the given seed. Those versions have no probing, so any pair here silently merges two
+// stations
+fn seed_collisions(seed: u64) -> Vec<(String, String)> {
+    let city_names = std::fs::read_to_string(CITY_NAMES_PATH).unwrap();
+    let mut slots: HashMap<usize, &str> = HashMap::new();
+    let mut collisions = vec![];
+    for name in city_names.lines() {
+        let (key, _) = get_u64_key(name);
+        let slot = mix64(key).wrapping_mul(seed) as usize % 12_289;
+        if let Some(other) = slots.insert(slot, name) {
+            collisions.push((other.to_owned(), name.to_owned()));
+        }
+    }
+    return collisions;
+}
+
+// the committed station list has to stay collision free under the baked in seed 384. Run this
+// after editing city_names.txt, a failure means a new seed is needed (see find_seed)
+pub fn test_seed_collisions() {
+    let collisions = seed_collisions(384);
+    assert!(collisions.is_empty(), "seed 384 has collisions on city_names.txt: {:?}", collisions);
+    println!("seed collision check passed");
+}
+
+// ordered data with K=3: the window has to only cover each station's last 3 readings
+pub fn test_windowed() {
+    let path = temp_path("windowed.txt");
+    std::fs::write(&path, "Hamburg;-1.0\nAbha;30.0\nHamburg;12.0\nHamburg;2.0\nHamburg;5.0\nHamburg;8.0\nAbha;40.0\n").unwrap();
+
+    let result = crate::window::run_windowed(path.to_str().unwrap(), 3).unwrap();
+    assert_eq!(result, "{Abha=30.0/35.0/40.0 last3=30.0/35.0/40.0, Hamburg=-1.0/5.2/12.0 last3=2.0/5.0/8.0}");
+
+    // the window mean rounds like the lifetime one: a tie goes up and -0.05 is 0.0, not -0.1
+    std::fs::write(&path, "B;0.2\nC;-0.1\nB;0.3\nC;0.0\n").unwrap();
+    let result = crate::window::run_windowed(path.to_str().unwrap(), 2).unwrap();
+    assert_eq!(result, "{B=0.2/0.3/0.3 last2=0.2/0.3/0.3, C=-0.1/0.0/0.0 last2=-0.1/0.0/0.0}");
+
+    // an empty window has no stats, so it's refused up front instead of panicking
+    match crate::window::run_windowed(path.to_str().unwrap(), 0) {
+        Err(v15::BrcError::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput),
+        other => panic!("expected an invalid input error, got {:?}", other),
+    }
+    println!("windowed check passed");
+}
+
+// the same stations inserted in different orders (and into maps with different strategies) have
+// to compare equal, a changed aggregate or an extra station must not
+pub fn test_maps_equal() {
+    let lines: [(&[u8], i32); 6] = [(b"Hamburg", 120), (b"Abha", -35), ("Zürich".as_bytes(), 42), (b"Hamburg", -10), (b"Q", 7), (b"Abha", 300)];
+    let mut a = v15::CustomHashMap::new();
+    let mut b = v15::CustomHashMap::with_strategy(v15::Modulus::Prime, v15::KeyHash::Full);
+    for (name, temp) in lines {
+        a.get_mut(name).add_temp(temp, name);
+    }
+    for (name, temp) in lines.iter().rev() {
+        b.get_mut(name).add_temp(*temp, name);
+    }
+    assert!(v15::maps_equal(&a, &b));
+
+    b.get_mut(b"Q").add_temp(8, b"Q");
+    assert!(!v15::maps_equal(&a, &b));
+    a.get_mut(b"Q").add_temp(8, b"Q");
+    a.get_mut(b"Extra").add_temp(0, b"Extra");
+    assert!(!v15::maps_equal(&a, &b));
+    println!("maps_equal check passed");
+}
+
+// a 2 byte record separator, with the input split into two reads at every possible position, so
+// the separator itself ends up split across the read boundary at each line
+pub fn test_split_separator() {
+    use std::io::Read;
+
+    let lines = ["Hamburg;12.0", "Abha;-3.5", "Zürich;4.2", "Hamburg;-1.0", "Q;0.7"];
+    let expected = crate::stream::run_stream((lines.join("\n") + "\n").as_bytes()).unwrap();
+    let data = lines.join("\r\n") + "\r\n";
+    let data = data.as_bytes();
+
+    for split in 0..=data.len() {
+        let reader = (&data[..split]).chain(&data[split..]);
+        let result = crate::stream::run_stream_with_separator(reader, b"\r\n").unwrap();
+        assert_eq!(result, expected, "split at byte {}", split);
+    }
+    println!("split separator check passed");
+}
+
+// the unsorted output has to hold exactly the same entries as the sorted one, just in slot order
+pub fn test_unsorted_output() {
+    let path = temp_path("unsorted.txt");
+    let contents = (0..2_000)
+        .map(|i| format!("Station{};{}.{}\n", i % 40, i % 97 - 40, i % 10))
+        .collect::<String>();
+    std::fs::write(&path, contents).unwrap();
+    let path_str = path.to_str().unwrap();
+
+    let sorted = v15::run_config(path_str, &v15::RunConfig::new()).unwrap();
+    let unsorted = v15::run_config(path_str, &v15::RunConfig::new().sorted(false)).unwrap();
+    let entries = |output: &str| {
+        let mut entries = output[1..output.len() - 1].split(", ").map(str::to_owned).collect::<Vec<_>>();
+        entries.sort();
+        entries
+    };
+    assert_eq!(entries(&unsorted), entries(&sorted));
+    assert_eq!(entries(&sorted).len(), 40);
+    println!("unsorted output check passed");
+}
+
+// 10 segments over a 3 line file: most segments hold no complete line, they have to come out
+// empty and the merge must still see each of the 3 readings exactly once
+pub fn test_more_segments_than_lines() {
+    let path = temp_path("more_segments.txt");
+    std::fs::write(&path, "Hamburg;12.0\nAbha;-3.5\nHamburg;2.0").unwrap();
+    let path_str = path.to_str().unwrap();
+
+    let splits = v15::find_segment_splits(&File::open(&path).unwrap(), 10);
+    assert_eq!(splits.len(), 10);
+    let maps = splits
+        .iter()
+        .map(|&(start, end)| {
+            let map = v15::scan_segment(path_str, start as u64, end as u64);
+            if start == end {
+                assert_eq!(map.stations().count(), 0);
+            }
+            map
+        })
+        .collect::<Vec<_>>();
+
+    let merged = v15::merge_maps(maps);
+    assert_eq!(merged.stations().map(|data| data.count).sum::<u32>(), 3);
+    assert_eq!(v15::format_output(&merged), "{Abha=-3.5/-3.5/-3.5, Hamburg=2.0/7.0/12.0}");
+    println!("more segments than lines check passed");
+}
+
+// Kelvin and Fahrenheit readings have to aggregate as the equivalent Celsius ones. Every Kelvin
+// reading lands exactly between two tenths, those halves go to the even neighbour
+pub fn test_input_unit() {
+    let (kelvin_path, fahrenheit_path) = (temp_path("kelvin.txt"), temp_path("fahrenheit.txt"));
+    // 26.95, 0.05, -273.15 and 26.85 C
+    std::fs::write(&kelvin_path, "Hamburg;300.1\nHamburg;273.2\nAbha;0.0\nHamburg;300.0\n").unwrap();
+    std::fs::write(&fahrenheit_path, "Hamburg;212.0\nHamburg;32.0\nAbha;-40.0\nAbha;98.6\n").unwrap();
+
+    let kelvin = v15::RunConfig::new().input_unit(v15::Unit::Kelvin);
+    assert_eq!(v15::run_config(kelvin_path.to_str().unwrap(), &kelvin).unwrap(), "{Abha=-273.2/-273.2/-273.2, Hamburg=0.0/17.9/27.0}");
+    let fahrenheit = v15::RunConfig::new().input_unit(v15::Unit::Fahrenheit);
+    assert_eq!(v15::run_config(fahrenheit_path.to_str().unwrap(), &fahrenheit).unwrap(), "{Abha=-40.0/-1.5/37.0, Hamburg=0.0/50.0/100.0}");
+    println!("input unit check passed");
+}
+
+pub fn test_query_station() {
+    let path = temp_path("query_station.txt");
+    std::fs::write(&path, "Tokyo;35.6\nHamburg;12.0\nTokyo;-1.2\nTokyo;20.0\n").unwrap();
+    let path_str = path.to_str().unwrap();
+
+    let tokyo = crate::summary::query_station(path_str, b"Tokyo").unwrap().unwrap();
+    assert_eq!(tokyo.name, "Tokyo");
+    assert_eq!(tokyo.count, 3);
+    assert_eq!(format!("{:.1}/{:.1}/{:.1}", tokyo.min, tokyo.mean, tokyo.max), "-1.2/18.1/35.6");
+    assert_eq!(crate::summary::query_station(path_str, b"Abha").unwrap(), None);
+    println!("query station check passed");
+}
+
+// many threads doing read_at on the very same File at interleaved offsets. pread takes the offset
+// as an argument instead of using (and moving) the fd's shared one, so every read has to return
+// exactly the bytes at its own offset no matter how the threads interleave
+pub fn test_shared_file_reads() {
+    const NUM_THREADS: usize = 16;
+    const READS_PER_THREAD: usize = 2_000;
+    const BLOCK: usize = 4096;
+
+    // every 8 byte word holds its own offset, so a misplaced read is detectable
+    let path = temp_path("shared_reads.bin");
+    let contents = (0..4 * 1024 * 1024 / 8).flat_map(|i| (i as u64 * 8).to_le_bytes()).collect::<Vec<_>>();
+    std::fs::write(&path, &contents).unwrap();
+    let file = File::open(&path).unwrap();
+    let num_words = contents.len() / 8;
+
+    thread::scope(|s| {
+        for t in 0..NUM_THREADS {
+            let file = &file;
+            s.spawn(move || {
+                let mut buf = [0u8; BLOCK];
+                for i in 0..READS_PER_THREAD {
+                    let word = (t * 7_919 + i * 104_729) % (num_words - BLOCK / 8);
+                    let offset = word as u64 * 8;
+                    file.read_exact_at(&mut buf, offset).unwrap();
+                    for (j, chunk) in buf.chunks_exact(8).enumerate() {
+                        assert_eq!(u64::from_le_bytes(chunk.try_into().unwrap()), offset + j as u64 * 8, "thread {} read {}", t, i);
+                    }
+                }
+            });
+        }
+    });
+    println!("shared file reads check passed");
+}
+
+// percentiles of a known (triangular) distribution: exact at 1,999 buckets, within half a bucket
+// (0.45 degrees) at 200
+pub fn test_histogram_buckets() {
+    use crate::histogram::{FULL_RESOLUTION_BUCKETS, Histogram};
+
+    let mut state = 0x2545f4914f6cdd1du64;
+    let mut next = || {
+        state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (state >> 33) as i32 % 1_000
+    };
+    let mut temps = (0..100_000).map(|_| next() - next()).collect::<Vec<_>>();
+
+    let mut full = Histogram::new();
+    let mut coarse = Histogram::with_buckets(200);
+    for temp in &temps {
+        full.add(*temp);
+        coarse.add(*temp);
+    }
+    assert_eq!(full.num_buckets(), FULL_RESOLUTION_BUCKETS);
+    assert_eq!(coarse.num_buckets(), 200);
+
+    temps.sort();
+    let (mut full_error, mut coarse_error) = (0.0f64, 0.0f64);
+    for p in [0.0, 0.01, 0.1, 0.25, 0.5, 0.75, 0.9, 0.99, 1.0] {
+        let rank = ((p * temps.len() as f64).ceil() as usize).max(1);
+        let exact = temps[rank - 1] as f64;
+        full_error = full_error.max((full.percentile(p).unwrap() - exact).abs());
+        coarse_error = coarse_error.max((coarse.percentile(p).unwrap() - exact).abs());
+    }
+    println!("max percentile error in tenths: {} at {} buckets, {} at 200 buckets", full_error, FULL_RESOLUTION_BUCKETS, coarse_error);
+    assert_eq!(full_error, 0.0);
+    assert!(coarse_error <= 4.5);
+    assert!(Histogram::new().percentile(0.5).is_none());
+    println!("histogram buckets check passed");
+}
+
+// the committed demo fixture has to keep producing its committed expected output, or
+// `cargo run -- --demo` greets newcomers with a failure
+pub fn test_demo_fixture() {
+    let expected = std::fs::read_to_string(DEMO_RESULTS_PATH).unwrap();
+    assert_eq!(v15::run(DEMO_MEASUREMENTS_PATH), expected);
+    assert_eq!(crate::v14::run(DEMO_MEASUREMENTS_PATH), expected);
+    println!("demo fixture check passed");
+}
+
+// a slow mock source (each job sleeps before returning its map) with snapshots taken more often
+// than jobs finish: some snapshot has to land mid-run, and every snapshot has to match exactly
+// the first jobs_done jobs, never a partially merged one
+pub fn test_interim_snapshots() {
+    const NUM_JOBS: usize = 5;
+
+    let scan_job = |job: usize| {
+        thread::sleep(std::time::Duration::from_millis(60));
+        let mut map = v15::CustomHashMap::new();
+        let name = format!("Job{}", job);
+        for _ in 0..10 {
+            map.get_mut(name.as_bytes()).add_temp(job as i32 * 10, name.as_bytes());
+            map.get_mut(b"All").add_temp(job as i32 * 10, b"All");
+        }
+        Ok(map)
+    };
+    // jobs run in order on a single worker, so after n jobs the output is known
+    let expected = |jobs_done: usize| {
+        if jobs_done == 0 {
+            return "{}".to_owned();
+        }
+        let mut parts = vec![format!("All=0.0/{:.1}/{}.0", (jobs_done - 1) as f64 / 2.0, jobs_done - 1)];
+        parts.extend((0..jobs_done).map(|job| format!("Job{}={}.0/{}.0/{}.0", job, job, job, job)));
+        "{".to_owned() + &parts.join(", ") + "}"
+    };
+
+    let mut snapshots = Vec::new();
+    let merged = v15::aggregate_with_snapshots(NUM_JOBS, 1, v15::CustomHashMap::new(), scan_job, std::time::Duration::from_millis(25), |snapshot| snapshots.push(snapshot)).unwrap();
+
+    assert_eq!(v15::format_output(&merged), expected(NUM_JOBS));
+    assert!(snapshots.iter().any(|snapshot| snapshot.jobs_done > 0 && snapshot.jobs_done < NUM_JOBS), "no snapshot mid-run");
+    for snapshot in &snapshots {
+        assert_eq!(snapshot.jobs_total, NUM_JOBS);
+        assert_eq!(snapshot.output, expected(snapshot.jobs_done));
+    }
+
+    // and the real thing on a small file, which finishes before the first snapshot is due
+    let path = temp_path("snapshots.txt");
+    std::fs::write(&path, "Hamburg;12.0\nAbha;-3.5\nHamburg;2.0\n").unwrap();
+    let result = v15::run_with_snapshots(path.to_str().unwrap(), std::time::Duration::from_secs(10), |_| panic!("unexpected snapshot")).unwrap();
+    assert_eq!(result, "{Abha=-3.5/-3.5/-3.5, Hamburg=2.0/7.0/12.0}");
+    println!("interim snapshots check passed ({} snapshots)", snapshots.len());
+}
+
+// v14's find_char on buffers shorter than 48 bytes goes through zero padded SIMD loads. It has to
+// agree with memchr for every length and match position, for the delimiters and for a 0 target,
+// the one byte the padding could falsely match
+pub fn test_find_char_padded() {
+    for target in [b'\n', b';', 0] {
+        for len in 0..48 {
+            // a filler byte that is never the target, with the target at every position in turn
+            let filler = if target == 0 { b'a' } else { 0 };
+            let mut buf = vec![filler; len];
+            assert_eq!(crate::v14::find_char(&buf, target), memchr::memchr(target, &buf), "len {} target {}", len, target);
+            for pos in 0..len {
+                buf[pos] = target;
+                assert_eq!(crate::v14::find_char(&buf, target), memchr::memchr(target, &buf), "len {} pos {} target {}", len, pos, target);
+                buf[pos] = filler;
+            }
+        }
+    }
+    println!("find_char padded check passed");
+}
+
+// an uppercasing display transform: "hamburg" and "Hamburg" are still aggregated as two stations,
+// only the printed names change, and the order follows whichever names were asked for
+pub fn test_display_name() {
+    let path = temp_path("display_name.txt");
+    std::fs::write(&path, "hamburg;12.0\nBulawayo;8.9\nHamburg;2.0\nabha;-3.5\nhamburg;4.0\n").unwrap();
+    let path_str = path.to_str().unwrap();
+    let upper = |name: &[u8]| String::from_utf8_lossy(name).to_uppercase().into_bytes();
+
+    assert_eq!(v15::run_config(path_str, &v15::RunConfig::new()).unwrap(), "{Bulawayo=8.9/8.9/8.9, Hamburg=2.0/2.0/2.0, abha=-3.5/-3.5/-3.5, hamburg=4.0/8.0/12.0}");
+    let by_display = v15::RunConfig::new().display_name(upper, true);
+    assert_eq!(v15::run_config(path_str, &by_display).unwrap(), "{ABHA=-3.5/-3.5/-3.5, BULAWAYO=8.9/8.9/8.9, HAMBURG=2.0/2.0/2.0, HAMBURG=4.0/8.0/12.0}");
+    let by_original = v15::RunConfig::new().display_name(upper, false);
+    assert_eq!(v15::run_config(path_str, &by_original).unwrap(), "{BULAWAYO=8.9/8.9/8.9, HAMBURG=2.0/2.0/2.0, ABHA=-3.5/-3.5/-3.5, HAMBURG=4.0/8.0/12.0}");
+    println!("display name check passed");
+}
+
+// run_low_memory on a file 40x its read buffer has to give v15's output while the heap peak stays
+// far below the file size. Needs the dhat allocator, and is best called first thing in main: dhat's
+// peak can't be reset, so it's checked against the peak since the profiler started. That's also
+// why the file is written line by line and v15 (16 MiB buffers) only runs afterwards. Earlier big
+// allocations can only make the check fail, never pass wrongly
+#[cfg(feature = "dhat-heap")]
+pub fn test_low_memory() {
+    use std::io::Write;
+
+    const MAX_PEAK_BYTES: usize = 1024 * 1024;
+
+    // real station names, which the hash is tuned to be collision free on
+    let city_names = std::fs::read_to_string(CITY_NAMES_PATH).unwrap();
+    let names = city_names.lines().take(400).collect::<Vec<_>>();
+    let path = temp_path("low_memory.txt");
+    let mut writer = std::io::BufWriter::new(File::create(&path).unwrap());
+    for i in 0..800_000 {
+        writeln!(writer, "{};{}.{}", names[i % names.len()], (i % 197) as i32 - 98, i % 10).unwrap();
+    }
+    drop(writer);
+    let path_str = path.to_str().unwrap();
+
+    let before = dhat::HeapStats::get();
+    let result = crate::v14::run_low_memory(path_str).unwrap();
+    let after = dhat::HeapStats::get();
+    let peak = after.max_bytes.saturating_sub(before.curr_bytes);
+    println!("low memory peak: {} bytes for a {} byte file", peak, std::fs::metadata(&path).unwrap().len());
+    assert!(peak < MAX_PEAK_BYTES, "peak of {} bytes", peak);
+
+    assert_eq!(result, v15::run(path_str));
+    println!("low memory check passed");
+}
+
+// readings that sum to exactly zero: the mean has to print as 0.0 (never -0.0) while min and max
+// keep their signs
+pub fn test_zero_total_mean() {
+    let path = temp_path("zero_total.txt");
+    std::fs::write(&path, "Hamburg;-5.0\nHamburg;5.0\nAbha;12.3\nAbha;-0.3\nAbha;-12.0\nAbha;0.0\n").unwrap();
+    let path_str = path.to_str().unwrap();
+
+    let expected = "{Abha=-12.0/0.0/12.3, Hamburg=-5.0/0.0/5.0}";
+    assert_eq!(v15::run(path_str), expected);
+    assert_eq!(crate::v14::run(path_str), expected);
+    let json = v15::run_multi_format(path_str, &[v15::Format::Json]);
+    assert_eq!(json[0].1, "{\"Abha\":{\"min\":-12.0,\"mean\":0.0,\"max\":12.3},\"Hamburg\":{\"min\":-5.0,\"mean\":0.0,\"max\":5.0}}");
+    println!("zero total mean check passed");
+}
+
+// only the two allowed stations may show up, with exactly the aggregates a full run gives them
+pub fn test_allowlist() {
+    let path = temp_path("allowlist.txt");
+    std::fs::write(&path, "Hamburg;12.0\nAbha;-3.5\nZürich;4.2\nHamburg;-1.0\nAccra;30.1\nAbha;20.0\nHamburgo;7.7\n".repeat(500)).unwrap();
+    let path_str = path.to_str().unwrap();
+
+    let allowed = std::collections::HashSet::from([b"Hamburg".to_vec(), "Zürich".as_bytes().to_vec()]);
+    assert_eq!(v15::run_allowlist(path_str, &allowed), "{Hamburg=-1.0/5.5/12.0, Zürich=4.2/4.2/4.2}");
+    assert_eq!(v15::run_allowlist(path_str, &std::collections::HashSet::new()), "{}");
+    println!("allowlist check passed");
+}
+
+// the integer mean rounding and digit formatting against a float reference of the 1BRC rule
+// (Math.round, half towards positive infinity, on the mean in tenths) over many total/count pairs.
+// Tie means are exact in f64 at these sizes, so the reference can't be off by a float error
+pub fn test_exact_mean_formatting() {
+    let reference = |total: i64, count: i64| {
+        let tenths = (total as f64 / count as f64 + 0.5).floor();
+        format!("{:.1}", tenths / 10.0)
+    };
+
+    let mut data = v15::StationData::new();
+    data.min_temp = -999;
+    data.max_temp = 999;
+    data.name = Some(b"X".to_vec());
+    let mut checked = 0;
+    for count in (1..=60).chain([999, 1_000, 1_001, 20_000_000]) {
+        for total in (-2_500..=2_500).chain([-999 * 20_000, 999 * 20_000, i32::MIN as i64 + 1, i32::MAX as i64]) {
+            data.total = total;
+            data.count = count as u32;
+            let formatted = v15::format_fixed(data.rounded_mean(), 1);
+            assert_eq!(formatted, reference(total, count), "total {} count {}", total, count);
+            checked += 1;
+        }
+    }
+
+    // the ties and the near-zero negatives float formatting gets wrong
+    for (total, count, expected) in [(-5, 10, "0.0"), (-4, 10, "0.0"), (-6, 10, "-0.1"), (45, 2, "2.3"), (-45, 2, "-2.2"), (0, 7, "0.0")] {
+        data.total = total;
+        data.count = count;
+        assert_eq!(data.format_data_point(), format!("X=-99.9/{}/99.9", expected));
+    }
+    println!("exact mean formatting check passed ({} pairs)", checked);
+}
+
+// open_measurements' pre-check turns paths the workers can't read into specific errors instead of
+// a panic from inside a worker thread
+pub fn test_open_precheck() {
+    let dir = std::env::temp_dir();
+
+    let missing = temp_path("precheck_does_not_exist.txt");
+    let missing = missing.to_str().unwrap();
+    assert!(matches!(v15::try_run(missing), Err(v15::BrcError::NotFound(path)) if path == missing));
+
+    let dir_path = dir.to_str().unwrap();
+    assert!(matches!(v15::try_run(dir_path), Err(v15::BrcError::NotAFile(_))));
+
+    // procfs files report a size of 0 but have contents
+    assert!(matches!(v15::try_run("/proc/self/status"), Err(v15::BrcError::SizeMismatch { reported: 0, .. })));
+
+    // root ignores file permissions, so only check the error where the mode is actually enforced
+    let unreadable = temp_path("precheck_unreadable.txt");
+    std::fs::write(&unreadable, "Hamburg;12.0\n").unwrap();
+    std::fs::set_permissions(&unreadable, std::os::unix::fs::PermissionsExt::from_mode(0o000)).unwrap();
+    match v15::try_run(unreadable.to_str().unwrap()) {
+        Err(v15::BrcError::PermissionDenied(_)) => println!("unreadable file rejected"),
+        Ok(result) => {
+            assert_eq!(result, "{Hamburg=12.0/12.0/12.0}");
+            println!("running with permission to read anything, unreadable case skipped");
+        }
+        Err(e) => panic!("unexpected error for an unreadable file: {}", e),
+    }
+
+    // readable regular files still go through, including empty ones
+    let empty = temp_path("precheck_empty.txt");
+    std::fs::write(&empty, "").unwrap();
+    assert_eq!(v15::try_run(empty.to_str().unwrap()).unwrap(), "{}");
+
+    println!("open pre-check passed");
+}
+
+// writes the demo fixture's output into a shm object and reads it back through a separate
+// read-only mapping, like a consumer process would. Needs `--features shm`
+#[cfg(all(unix, feature = "shm"))]
+pub fn test_shm_output() {
+    let (map, _stats) = v15::aggregate_file(DEMO_MEASUREMENTS_PATH, &v15::RunOptions::default()).unwrap();
+    let expected = v15::format_output(&map);
+    let name = format!("brc_shm_output_{}", std::process::id());
+
+    let output = crate::shm::write_output_to_shm(&map, &name).unwrap();
+    assert_eq!(output.as_bytes(), expected.as_bytes());
+    assert_eq!(crate::shm::read_output_from_shm(&name).unwrap(), expected);
+
+    // writing again into the same name replaces the contents, including a shorter output
+    let rewritten = crate::shm::write_output_to_shm(&v15::CustomHashMap::new(), &name).unwrap();
+    assert_eq!(crate::shm::read_output_from_shm(&name).unwrap(), "{}");
+
+    drop(output);
+    rewritten.unlink().unwrap();
+    assert!(crate::shm::read_output_from_shm(&name).is_err());
+    println!("shm output check passed");
+}
+
+// the run-length fast path against the normal scan on a file made of long runs of identical lines,
+// long enough that runs straddle the 16 MiB read chunks and the segment splits
+pub fn test_collapse_runs() {
+    let city_names = std::fs::read_to_string(CITY_NAMES_PATH).unwrap();
+    let names: Vec<_> = city_names.lines().take(200).collect();
+
+    let mut contents = String::new();
+    let mut rng = 0x2545f4914f6cdd1du64;
+    while contents.len() < 40 * 1024 * 1024 {
+        rng ^= rng << 13;
+        rng ^= rng >> 7;
+        rng ^= rng << 17;
+        let name = names[rng as usize % names.len()];
+        let temp = (rng >> 20) as i32 % 1999 - 999;
+        let line = format!("{};{}{}.{}\n", name, if temp < 0 { "-" } else { "" }, temp.abs() / 10, temp.abs() % 10);
+        // mostly long runs, with single lines in between so runs also end on a changed temp only
+        let run_len = if rng.is_multiple_of(4) { 1 } else { (rng >> 40) as usize % 5_000 + 1 };
+        contents += &line.repeat(run_len);
+        if rng.is_multiple_of(3) {
+            contents += &format!("{};{}.5\n", name, temp.abs() / 10);
+        }
+    }
+    let path = temp_path("collapse_runs.txt");
+    std::fs::write(&path, &contents).unwrap();
+    let path_str = path.to_str().unwrap();
+
+    let expected = v15::run_config(path_str, &v15::RunConfig::new()).unwrap();
+    assert_eq!(v15::run_config(path_str, &v15::RunConfig::new().collapse_runs(true)).unwrap(), expected);
+
+    // same temp on a different station, and the same station with a different temp, both break a run
+    std::fs::write(&path, "Abha;1.0\n".repeat(1_000) + "Abha;2.0\n" + &"Accra;2.0\n".repeat(3) + "Abha;2.0\n").unwrap();
+    assert_eq!(v15::run_config(path_str, &v15::RunConfig::new().collapse_runs(true)).unwrap(), "{Abha=1.0/1.0/2.0, Accra=2.0/2.0/2.0}");
+    println!("collapse runs check passed");
+}
+
+// run_sorted_input against the general path on a name sorted file big enough for several segments,
+// and on unsorted input, which debug builds have to reject while release builds still get right
+pub fn test_sorted_input() {
+    let city_names = std::fs::read_to_string(CITY_NAMES_PATH).unwrap();
+    let mut names: Vec<_> = city_names.lines().take(300).collect();
+    names.sort();
+
+    // byte order, which puts "Zürich" after "Zagreb" and before any multibyte-led name
+    let mut contents = String::new();
+    for (i, name) in names.iter().enumerate() {
+        for j in 0..(i * 37 % 5_000 + 1) {
+            let temp = (i * 131 + j * 17) as i32 % 1999 - 999;
+            contents += &format!("{};{}{}.{}\n", name, if temp < 0 { "-" } else { "" }, temp.abs() / 10, temp.abs() % 10);
+        }
+    }
+    let path = temp_path("sorted_input.txt");
+    std::fs::write(&path, &contents).unwrap();
+    let path_str = path.to_str().unwrap();
+    assert_eq!(v15::run_sorted_input(path_str), v15::run(path_str));
+
+    // a station coming back after another one
+    std::fs::write(&path, "Abha;1.0\nAbha;3.0\nAccra;2.0\nAbha;5.0\n").unwrap();
+    let unsorted = std::panic::catch_unwind(|| v15::run_sorted_input(path_str));
+    if cfg!(debug_assertions) {
+        assert!(unsorted.is_err(), "unsorted input wasn't detected");
+    } else {
+        assert_eq!(unsorted.unwrap(), "{Abha=1.0/3.0/5.0, Accra=2.0/2.0/2.0}");
+        println!("release build, the unsorted check only runs in debug builds");
+    }
+    println!("sorted input check passed");
+}
+
+// grouped output into a Vec: one line per first character, groups in code point order (multibyte
+// first characters after ASCII ones) and stations sorted within their group
+pub fn test_grouped_output() {
+    let path = temp_path("grouped_output.txt");
+    std::fs::write(&path, "Zürich;4.2\nAccra;30.1\nÜrümqi;-7.0\nZagreb;11.0\nAbha;-3.5\nBulawayo;18.9\nÖrebro;2.0\nAbha;20.0\n").unwrap();
+    let path_str = path.to_str().unwrap();
+
+    let mut out = Vec::new();
+    v15::run_grouped(path_str, &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert_eq!(out, concat!(
+        "A: Abha=-3.5/8.3/20.0, Accra=30.1/30.1/30.1\n",
+        "B: Bulawayo=18.9/18.9/18.9\n",
+        "Z: Zagreb=11.0/11.0/11.0, Zürich=4.2/4.2/4.2\n",
+        "Ö: Örebro=2.0/2.0/2.0\n",
+        "Ü: Ürümqi=-7.0/-7.0/-7.0\n",
+    ));
+
+    // every station shows up exactly once, in the same order as the canonical output
+    let entries = out.lines().flat_map(|line| line.split_once(": ").unwrap().1.split(", ")).collect::<Vec<_>>();
+    assert_eq!("{".to_owned() + &entries.join(", ") + "}", v15::run(path_str));
+    println!("grouped output check passed");
+}
+
+// SortKey::NaturalName puts numbered stations in numeric order, while the default stays bytewise
+pub fn test_natural_sort() {
+    use std::cmp::Ordering::{Equal, Greater, Less};
+    for (a, b, expected) in [
+        ("Station2", "Station10", Less),
+        ("Station10", "Station9", Greater),
+        ("Station007", "Station7", Equal),
+        ("Station1b", "Station1a", Greater),
+        ("Km2 North", "Km10 North", Less),
+        ("Station", "Station1", Less),
+        ("99999999999999999999999", "100000000000000000000000", Less),
+        ("Abha", "Accra", Less),
+    ] {
+        assert_eq!(v15::natural_cmp(a.as_bytes(), b.as_bytes()), expected, "{} vs {}", a, b);
+    }
+
+    let path = temp_path("natural_sort.txt");
+    std::fs::write(&path, "Station10;1.0\nStation2;2.0\nStation1;3.0\nStation100;4.0\nAlpha;5.0\n").unwrap();
+    let path_str = path.to_str().unwrap();
+
+    let natural = v15::run_config(path_str, &v15::RunConfig::new().sort_by(v15::SortKey::NaturalName)).unwrap();
+    assert_eq!(natural, "{Alpha=5.0/5.0/5.0, Station1=3.0/3.0/3.0, Station2=2.0/2.0/2.0, Station10=1.0/1.0/1.0, Station100=4.0/4.0/4.0}");
+    // the default sorts the formatted entries, where "=" sorts after "0"
+    let bytewise = v15::run_config(path_str, &v15::RunConfig::new()).unwrap();
+    assert_eq!(bytewise, "{Alpha=5.0/5.0/5.0, Station100=4.0/4.0/4.0, Station10=1.0/1.0/1.0, Station1=3.0/3.0/3.0, Station2=2.0/2.0/2.0}");
+    println!("natural sort check passed");
+}
+
+// v16's per-worker busy/idle split on a skewed file: the first 16 MiB chunk is all short lines
+// (lots of lines to parse per byte), the rest longer ones, and there are fewer chunks than workers
+// would need to stay busy. The least busy worker has to report idle time
+pub fn test_v16_worker_idle() {
+    let mut contents = String::new();
+    while contents.len() < 16 * 1024 * 1024 {
+        contents += "Abha;1.0\nBonn;-2.5\nJos;33.1\n";
+    }
+    // v16's find_char only looks at the first 48 bytes of a line
+    let long_line = "Ouagadougou Long Station;12.3\n";
+    while contents.len() < 40 * 1024 * 1024 {
+        contents += long_line;
+    }
+    let path = temp_path("v16_worker_idle.txt");
+    std::fs::write(&path, &contents).unwrap();
+
+    let (_result, metrics) = crate::v16::run_with_metrics(path.to_str().unwrap());
+    for (i, worker) in metrics.workers.iter().enumerate() {
+        println!("worker {}: busy {:?}, idle {:?} ({:.0}% idle)", i, worker.busy, worker.idle, 100.0 * worker.idle_ratio());
+        assert!((0.0..=1.0).contains(&worker.idle_ratio()));
+    }
+    let fastest = metrics.workers.iter().min_by_key(|worker| worker.busy).unwrap();
+    assert!(fastest.idle > std::time::Duration::ZERO, "the least busy worker reported no idle time");
+    println!("v16 worker idle check passed");
+}
+
+// a map from the small prime table merged into one from the large power of two table has to end up
+// with the same stations as if everything had gone into one map, both through merge_by_name
+// directly and through merge_maps, which has to notice the layouts differ
+pub fn test_merge_by_name() {
+    use v15::{CustomHashMap, KeyHash, Modulus};
+    let city_names = std::fs::read_to_string(CITY_NAMES_PATH).unwrap();
+    // any names will do: colliding ones are probed into different slots in each table, which
+    // merging by name has to cope with too
+    let names: Vec<_> = city_names.lines().take(300).collect();
+    assert_eq!(names.len(), 300);
+
+    // (small, large, everything in one map), with overlapping halves of the names so some stations
+    // are in both maps and some in only one
+    let build = || {
+        let mut small = CustomHashMap::with_strategy(Modulus::Prime, KeyHash::Full);
+        let mut large = CustomHashMap::with_strategy(Modulus::PowerOfTwo, KeyHash::Full);
+        let mut expected = CustomHashMap::with_strategy(Modulus::PowerOfTwo, KeyHash::Full);
+        for (i, name) in names.iter().enumerate() {
+            let temp = (i * 37 % 1999) as i32 - 999;
+            if i < 200 {
+                small.get_mut(name.as_bytes()).add_temp(temp, name.as_bytes());
+                expected.get_mut(name.as_bytes()).add_temp(temp, name.as_bytes());
+            }
+            if i >= 100 {
+                large.get_mut(name.as_bytes()).add_temp(-temp / 2, name.as_bytes());
+                expected.get_mut(name.as_bytes()).add_temp(-temp / 2, name.as_bytes());
+            }
+        }
+        (small, large, v15::format_output(&expected))
+    };
+
+    let (small, mut large, expected) = build();
+    assert_eq!(small.backing().len(), 12_289);
+    assert_eq!(large.backing().len(), 32_768);
+    v15::merge_by_name(&mut large, small);
+    assert_eq!(v15::format_output(&large), expected);
+
+    let (small, large, expected) = build();
+    assert_eq!(v15::format_output(&v15::merge_maps(vec![large, small])), expected);
+
+    println!("merge by name check passed");
+}
+
+// every subcommand string has to parse to its command with its flags, in any order, and the
+// old flag-only invocations still mean run
+pub fn test_cli_dispatch() {
+    use crate::cli::{Command, Version, parse_args};
+    let parse = |args: &str| parse_args(&args.split_whitespace().map(str::to_owned).collect::<Vec<_>>());
+
+    assert_eq!(parse(""), Ok(Command::Run { demo: false, repeat: 1, checksum: false }));
+    assert_eq!(parse("--demo"), Ok(Command::Run { demo: true, repeat: 1, checksum: false }));
+    assert_eq!(parse("--repeat 3 --demo"), Ok(Command::Run { demo: true, repeat: 3, checksum: false }));
+    assert_eq!(parse("run --repeat 2 --checksum"), Ok(Command::Run { demo: false, repeat: 2, checksum: true }));
+    assert_eq!(parse("validate m.txt"), Ok(Command::Validate { path: "m.txt".to_owned() }));
+    assert_eq!(parse("count m.txt"), Ok(Command::Count { path: "m.txt".to_owned() }));
+    assert_eq!(parse("bench m.txt"), Ok(Command::Bench { path: "m.txt".to_owned(), runs: 5, version: Version::V16 }));
+    assert_eq!(parse("bench --version v15 m.txt --runs 10"), Ok(Command::Bench { path: "m.txt".to_owned(), runs: 10, version: Version::V15 }));
+    assert_eq!(parse("verify out.txt"), Ok(Command::Verify { results_path: "out.txt".to_owned(), expected_path: None }));
+    assert_eq!(parse("verify --expected ref.txt out.txt"), Ok(Command::Verify { results_path: "out.txt".to_owned(), expected_path: Some("ref.txt".to_owned()) }));
+    assert_eq!(parse("help"), Ok(Command::Help));
+    assert_eq!(parse("--help"), Ok(Command::Help));
+
+    for bad in ["frobnicate", "validate", "count a.txt b.txt", "run --repeat", "run --repeat 0", "bench m.txt --version v9", "run --verbose"] {
+        assert!(parse(bad).is_err(), "\"{}\" should not parse", bad);
+    }
+    println!("cli dispatch check passed");
+}
+
+// ".5" and "-.5" read as 0.5 and -0.5 through the lenient, single pass and strict parsers and a
+// full run, while a value with no digit after the point is still a strict mode error
+pub fn test_missing_integer_digit() {
+    for (temp, expected) in [(".5", 5), ("-.5", -5), ("0.5", 5), ("-0.5", -5), (".0", 0), ("-.9", -9)] {
+        assert_eq!(v15::parse_temp(temp.as_bytes()), expected, "{}", temp);
+        assert_eq!(v15::parse_temp_strict(b"Hamburg", temp.as_bytes()).unwrap(), expected, "{}", temp);
+        let line = format!("Hamburg;{}\n", temp);
+        assert_eq!(v15::scan_line(line.as_bytes(), 0), Some((&b"Hamburg"[..], expected, line.len())));
+    }
+    for bad in [".", "-.", "-", "1."] {
+        assert!(v15::parse_temp_strict(b"Hamburg", bad.as_bytes()).is_err(), "{}", bad);
+    }
+
+    let path = temp_path("missing_integer_digit.txt");
+    std::fs::write(&path, "Hamburg;.5\nHamburg;-.5\nAccra;-.9\nHamburg;1.5\n".repeat(100)).unwrap();
+    let path_str = path.to_str().unwrap();
+    let expected = "{Accra=-0.9/-0.9/-0.9, Hamburg=-0.5/0.5/1.5}";
+    assert_eq!(v15::run(path_str), expected);
+    let strict = v15::RunOptions { strict: true, ..Default::default() };
+    assert_eq!(v15::run_with_options(path_str, &strict).unwrap(), expected);
+    println!("missing integer digit check passed");
+}
+
+// equal outputs have to give equal checksums, and changing, adding or dropping a single character
+// anywhere (including in the last, partial 8 byte block) has to change it
+pub fn test_output_checksum() {
+    let output = v15::run(DEMO_MEASUREMENTS_PATH);
+    let checksum = verify::output_checksum(&output);
+    assert_eq!(verify::output_checksum(&v15::run(DEMO_MEASUREMENTS_PATH)), checksum);
+
+    let mut variants = vec![output[..output.len() - 1].to_owned(), output.clone() + " ", "{}".to_owned(), String::new()];
+    for pos in [0, 1, 7, 8, output.len() / 2, output.len() - 2, output.len() - 1] {
+        let mut bytes = output.clone().into_bytes();
+        bytes[pos] ^= 1;
+        variants.push(String::from_utf8_lossy(&bytes).into_owned());
+    }
+    for variant in &variants {
+        assert_ne!(verify::output_checksum(variant), checksum, "\"{}\"", variant);
+    }
+    println!("output checksum {:016x}, check passed", checksum);
+}
+
+// 50 million -99.9 readings for one station sum to -49,950,000,000 tenths, which wrapped an i32
+// total into a garbage mean. Built by merging a 10,000 reading station into the map 5,000 times,
+// the same merge_with the threaded merges go through, instead of scanning a 700 MB file
+pub fn test_wide_total() {
+    const LINES: usize = 50_000_000;
+    const BLOCK_LINES: usize = 10_000;
+
+    let block = v15::StationData::from_samples(b"Hamburg", &[-999; BLOCK_LINES]);
+    let mut map = v15::CustomHashMap::new();
+    for _ in 0..LINES / BLOCK_LINES {
+        map.get_mut(b"Hamburg").merge_with(&block);
+    }
+
+    let data = map.get_mut(b"Hamburg");
+    assert_eq!(data.total, -999 * LINES as i64);
+    assert_eq!(data.count as usize, LINES);
+    assert_eq!(data.rounded_mean(), -999);
+    assert_eq!(v15::format_output(&map), "{Hamburg=-99.9/-99.9/-99.9}");
+    println!("wide total check passed");
+}
+
+// Westminster and Westchester share their length and first and last three bytes, all the sampled
+// key looks at, so they land in the same slot in every version. Probing has to keep them apart
+// through the scan and through the merges
+pub fn test_colliding_names() {
+    let (first, second) = (b"Westminster".as_slice(), b"Westchester".as_slice());
+    let mut map = v15::CustomHashMap::with_strategy(v15::Modulus::PowerOfTwo, v15::KeyHash::Sampled);
+    let home = map.slot_index(first);
+    assert_eq!(map.slot_index(second), home);
+    map.get_mut(first).add_temp(123, first);
+    map.get_mut(second).add_temp(-45, second);
+    assert_eq!(map.probe(first), (home, 0));
+    assert_eq!(map.probe(second), ((home + 1) % map.backing().len(), 1));
+
+    let path = temp_path("colliding_names.txt");
+    std::fs::write(&path, "Westminster;12.3\nHamburg;8.0\nWestchester;-4.5\n".repeat(1_000)).unwrap();
+    let path_str = path.to_str().unwrap();
+
+    let expected = "{Hamburg=8.0/8.0/8.0, Westchester=-4.5/-4.5/-4.5, Westminster=12.3/12.3/12.3}";
+    assert_eq!(crate::v11::run(path_str), expected);
+    assert_eq!(crate::v12::run(path_str), expected);
+    assert_eq!(crate::v13::run(path_str), expected);
+    assert_eq!(crate::v14::run(path_str), expected);
+    assert_eq!(v15::run(path_str), expected);
+    assert_eq!(crate::v16::run(path_str), expected);
+    assert_eq!(v15::run_config(path_str, &v15::RunConfig::new().threads(3)).unwrap(), expected);
+
+    // the same two stations in opposite slots in two maps still merge by name
+    let segment_map = |names: [&[u8]; 2]| {
+        let mut map = v15::CustomHashMap::with_strategy(v15::Modulus::Prime, v15::KeyHash::Sampled);
+        for name in names {
+            map.get_mut(name).add_temp(name.len() as i32, name);
+        }
+        map
+    };
+    let merged = v15::merge_maps(vec![segment_map([first, second]), segment_map([second, first])]);
+    assert_eq!(merged.stations().count(), 2);
+    assert!(merged.stations().all(|data| data.count == 2));
+
+    let allowed = std::collections::HashSet::from([second.to_vec()]);
+    assert_eq!(v15::run_allowlist(path_str, &allowed), "{Westchester=-4.5/-4.5/-4.5}");
+    println!("colliding names check passed");
+}
+
+// a temperature slice from a \r\n file ends in \r. The parsers have to read it like the same
+// temperature without it, and the checks that go by the slice's length (strict parsing,
+// detect_format's fractional digit count) must not count it
+pub fn test_trailing_cr() {
+    for (temp, expected) in [("12.3\r", 123), ("-12.3\r", -123), ("0.0\r", 0), ("-.5\r", -5), ("9.9\r", 99)] {
+        assert_eq!(v15::parse_temp(temp.as_bytes()), expected, "{:?}", temp);
+        assert_eq!(v15::parse_temp_strict(b"Hamburg", temp.as_bytes()).unwrap(), expected, "{:?}", temp);
+        let line = format!("Hamburg;{}\n", temp);
+        assert_eq!(v15::scan_line(line.as_bytes(), 0), Some((&b"Hamburg"[..], expected, line.len())));
+    }
+    assert!(v15::parse_temp_strict(b"Hamburg", b"12.\r").is_err());
+
+    let path = temp_path("trailing_cr.txt");
+    std::fs::write(&path, "Hamburg;12.3\r\nAccra;-4.5\r\nHamburg;-0.1\r\n".repeat(200)).unwrap();
+    let path_str = path.to_str().unwrap();
+    let expected = "{Accra=-4.5/-4.5/-4.5, Hamburg=-0.1/6.1/12.3}";
+    assert_eq!(v15::run(path_str), expected);
+    let strict = v15::RunOptions { strict: true, ..Default::default() };
+    assert_eq!(v15::run_with_options(path_str, &strict).unwrap(), expected);
+    assert_eq!(v15::detect_format(path_str, 100).unwrap().fractional_digits, 1);
+    assert_eq!(v15::run_auto(path_str).unwrap(), expected);
+    assert_eq!(crate::v16::run(path_str), expected);
+    println!("trailing \\r check passed");
+}
+
+// measurements.log and measurements.log.1 are merged, the missing .2 is skipped and .3 (past
+// max_index) is left alone
+pub fn test_rotated() {
+    let base = temp_path("rotated.log");
+    let base_str = base.to_str().unwrap();
+    std::fs::write(&base, "Hamburg;12.0\nAccra;30.1\n").unwrap();
+    // the rotations are named after the base file, wrapping them in TempPath removes them too
+    let first = TempPath(PathBuf::from(format!("{}.1", base_str)));
+    let third = TempPath(PathBuf::from(format!("{}.3", base_str)));
+    std::fs::write(&first, "Hamburg;-2.0\nAbha;5.5\n").unwrap();
+    std::fs::write(&third, "Hamburg;99.9\n").unwrap();
+
+    assert_eq!(v15::run_rotated(base_str, 2), "{Abha=5.5/5.5/5.5, Accra=30.1/30.1/30.1, Hamburg=-2.0/5.0/12.0}");
+    assert_eq!(v15::run_rotated(base_str, 0), "{Accra=30.1/30.1/30.1, Hamburg=12.0/12.0/12.0}");
+    assert_eq!(v15::run_rotated(base_str, 3), "{Abha=5.5/5.5/5.5, Accra=30.1/30.1/30.1, Hamburg=-2.0/36.6/99.9}");
+    println!("rotated files check passed");
+}
+
+// means that land exactly on a hundredth ending in 5 have to round towards positive infinity in
+// every version, not to even: -0.05 -> 0.0 (without a minus sign), 2.25 -> 2.3, -2.35 -> -2.3
+pub fn test_round_half_up() {
+    let path = temp_path("round_half_up.txt");
+    std::fs::write(&path, "Abha;-0.1\nAbha;0.0\nBonn;2.2\nBonn;2.3\nCork;-2.3\nCork;-2.4\n".repeat(100)).unwrap();
+    let path_str = path.to_str().unwrap();
+
+    let expected = "{Abha=-0.1/0.0/0.0, Bonn=2.2/2.3/2.3, Cork=-2.4/-2.3/-2.3}";
+    assert_eq!(crate::v11::run(path_str), expected);
+    assert_eq!(crate::v12::run(path_str), expected);
+    assert_eq!(crate::v13::run(path_str), expected);
+    assert_eq!(crate::v14::run(path_str), expected);
+    assert_eq!(v15::run(path_str), expected);
+    assert_eq!(crate::v16::run(path_str), expected);
+    println!("round half up check passed");
+}
+
+// Oymyakon swings across most of the temperature range while the others barely move, so it has to
+// come first; Accra and Lima tie on span and keep name order
+pub fn test_span() {
+    let path = temp_path("span.txt");
+    std::fs::write(&path, "Lima;18.0\nOymyakon;-65.4\nAccra;27.5\nLima;19.5\nOymyakon;31.6\nAccra;29.0\nHamburg;2.0\nHamburg;12.0\n".repeat(10)).unwrap();
+
+    let spans = crate::summary::run_by_span(path.to_str().unwrap());
+    let names: Vec<_> = spans.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(names, ["Oymyakon", "Hamburg", "Accra", "Lima"]);
+    assert_eq!(format!("{:.1}", spans[0].1), "97.0");
+    assert_eq!(format!("{:.1}", spans[1].1), "10.0");
+    assert_eq!(format!("{:.1}", spans[3].1), "1.5");
+    println!("span check passed");
+}
+
+// v17 against v15 on the demo fixture and on the edge cases the mapped scan has to handle itself:
+// no trailing newline, 1 byte and 100 byte names, a blank last line, an empty file. The splits are checked directly
+// since a single core machine only ever uses one segment
+pub fn test_v17() {
+    assert_eq!(crate::v17::run(DEMO_MEASUREMENTS_PATH), v15::run(DEMO_MEASUREMENTS_PATH));
+
+    let path = temp_path("v17.txt");
+    let path_str = path.to_str().unwrap();
+    let long_name = "x".repeat(100);
+    let contents = format!("A;1.0\n{};-99.9\nHamburg;12.3\n{};99.9\nA;-2.0", long_name, long_name);
+    std::fs::write(&path, &contents).unwrap();
+    assert_eq!(crate::v17::run(path_str), format!("{{A=-2.0/-0.5/1.0, Hamburg=12.3/12.3/12.3, {}=-99.9/0.0/99.9}}", long_name));
+    std::fs::write(&path, "Hamburg;12.0\n\n").unwrap();
+    assert_eq!(crate::v17::run(path_str), "{Hamburg=12.0/12.0/12.0}");
+    std::fs::write(&path, "").unwrap();
+    assert_eq!(crate::v17::run(path_str), "{}");
+
+    for num_segments in [1, 2, 3, 7, 50] {
+        let splits = crate::mmap::find_segment_splits(contents.as_bytes(), num_segments);
+        assert_eq!(splits.len(), num_segments);
+        assert_eq!(splits[0].0, 0);
+        assert_eq!(splits.last().unwrap().1, contents.len());
+        for pair in splits.windows(2) {
+            assert_eq!(pair[0].1, pair[1].0);
+            assert!(pair[0].1 == contents.len() || contents.as_bytes()[pair[0].1 - 1] == b'\n');
+        }
+    }
+    println!("v17 check passed");
+}
+
+// mean_half_up on exact halves (and their neighbours) of positive, negative and zero means:
+// halves go towards positive infinity, everything else to the nearest tenth
+pub fn test_mean_tie_table() {
+    let table: &[(i64, u32, &str)] = &[
+        // positive halves: 1.25, 0.05, 2.25, 99.85
+        (25, 2, "1.3"), (5, 10, "0.1"), (45, 2, "2.3"), (1997, 2, "99.9"),
+        // negative halves: -1.25, -0.05, -2.35, -99.85
+        (-25, 2, "-1.2"), (-5, 10, "0.0"), (-47, 2, "-2.3"), (-1997, 2, "-99.8"),
+        // zero and halves around it: 0.0, -0.15, 0.15
+        (0, 1, "0.0"), (0, 4, "0.0"), (-3, 2, "-0.1"), (3, 2, "0.2"),
+        // just off a half on either side: 1.2499.., 1.2500..1, -1.2499.., -1.2500..1
+        (12_499, 1_000, "1.2"), (12_501, 1_000, "1.3"), (-12_499, 1_000, "-1.2"), (-12_501, 1_000, "-1.3"),
+        // halves that only show up with odd counts > 2 or huge counts
+        (15, 6, "0.3"), (-15, 6, "-0.2"), (-999 * 1_000_000_000 + 500_000_000, 1_000_000_000, "-99.8"),
+        // whole tenths stay put
+        (-999, 1, "-99.9"), (999, 1, "99.9"), (-20, 2, "-1.0"),
+    ];
+    for &(total, count, expected) in table {
+        assert_eq!(v15::format_fixed(v15::mean_half_up(total, count), 1), expected, "total {} count {}", total, count);
+    }
+    println!("mean tie table check passed ({} cases)", table.len());
+}
+
+// runs over splits computed once have to match normal runs, with any config, and splits for a
+// file that has since changed length are rejected
+pub fn test_precomputed_splits() {
+    let path = temp_path("precomputed_splits.txt");
+    std::fs::copy(DEMO_MEASUREMENTS_PATH, &path).unwrap();
+    let path_str = path.to_str().unwrap();
+
+    for num_segments in [1, 3, 8] {
+        let splits = v15::precompute_splits(path_str, num_segments);
+        assert_eq!(splits.len(), num_segments);
+        for config in [v15::RunConfig::new(), v15::RunConfig::new().threads(2).sort_by(v15::SortKey::Mean), v15::RunConfig::new().format(v15::Format::Json)] {
+            assert_eq!(v15::run_with_splits(path_str, &splits, &config).unwrap(), v15::run_config(path_str, &config).unwrap());
+        }
+    }
+    assert_eq!(v15::run_with_splits(path_str, &v15::precompute_splits(path_str, 4), &v15::RunConfig::new()).unwrap(), v15::run(path_str));
+
+    let splits = v15::precompute_splits(path_str, 4);
+    std::io::Write::write_all(&mut std::fs::OpenOptions::new().append(true).open(&path).unwrap(), b"Hamburg;12.0\n").unwrap();
+    assert!(matches!(v15::run_with_splits(path_str, &splits, &v15::RunConfig::new()), Err(v15::BrcError::StaleSplits { .. })));
+    assert!(matches!(v15::run_with_splits(path_str, &[], &v15::RunConfig::new()), Err(v15::BrcError::StaleSplits { .. })));
+    println!("precomputed splits check passed");
+}
+
+// a split point landing at the start of a line with a 100 byte name has its newline 106 bytes
+// away, past the first 64 byte read of the boundary search
+pub fn test_long_line_at_split() {
+    let path = temp_path("long_line_at_split.txt");
+    let path_str = path.to_str().unwrap();
+    let long_name = "y".repeat(100);
+    let long_line = format!("{};-12.3\n", long_name);
+
+    // two segments over two lines: the search starts at the second line's name and the first
+    // newline after it ends the file, so the second segment comes out empty
+    std::fs::write(&path, long_line.repeat(2)).unwrap();
+    let file_len = 2 * long_line.len() as u64;
+    assert_eq!(v15::precompute_splits(path_str, 2), [(0, file_len), (file_len, file_len)]);
+
+    // boundaries at every offset within the long lines, and in a file ending without a newline
+    let contents = format!("{}Hamburg;1.0\n{}{}", long_line, long_line, long_line.trim_end());
+    std::fs::write(&path, &contents).unwrap();
+    let expected = format!("{{Hamburg=1.0/1.0/1.0, {}=-12.3/-12.3/-12.3}}", long_name);
+    for num_segments in 1..=contents.len() / 4 {
+        let splits = v15::precompute_splits(path_str, num_segments);
+        assert_eq!(v15::run_with_splits(path_str, &splits, &v15::RunConfig::new()).unwrap(), expected, "{} segments", num_segments);
+    }
+    println!("long line at split check passed");
+}
+
+// v14_par's seek + read splitter on the files that used to panic it: two lines split 7 ways, so
+// most search starts are past the last newline, and 92 byte names whose newline is past the first
+// 64 byte read
+pub fn test_v14_par_splits() {
+    let path = temp_path("v14_par_splits.txt");
+    let path_str = path.to_str().unwrap();
+
+    std::fs::write(&path, "Hamburg;12.0\nBulawayo;8.9\n").unwrap();
+    assert_eq!(crate::v14_par::run(path_str), "{Bulawayo=8.9/8.9/8.9, Hamburg=12.0/12.0/12.0}");
+
+    let long_name = "z".repeat(92);
+    std::fs::write(&path, format!("{};1.5\nHamburg;12.0\n{};-3.5\n", long_name, long_name).repeat(3)).unwrap();
+    assert_eq!(crate::v14_par::run(path_str), v15::run(path_str));
+    println!("v14_par splits check passed");
+}
+
+// the default KeySampling has to put every name exactly where Sampled does, and sampling the
+// middle of the name instead separates Westminster and Westchester, which the default ends and
+// length can't tell apart
+pub fn test_key_sampling() {
+    let sampled = v15::CustomHashMap::with_strategy(v15::Modulus::PowerOfTwo, v15::KeyHash::Sampled);
+    let default_at = v15::CustomHashMap::with_strategy(v15::Modulus::PowerOfTwo, v15::KeyHash::SampledAt(v15::KeySampling::default()));
+    let city_names = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/city_names.txt")).unwrap();
+    for name in city_names.lines().chain(["A", "Ab", "Abc", "Zürich"]) {
+        assert_eq!(default_at.slot_index(name.as_bytes()), sampled.slot_index(name.as_bytes()), "{}", name);
+    }
+
+    let (first, second) = (b"Westminster".as_slice(), b"Westchester".as_slice());
+    let middle = v15::KeyHash::SampledAt(v15::KeySampling::parse("0, 4, 5, 6, -2, -1").unwrap());
+    for (key_hash, expected_chain) in [(v15::KeyHash::Sampled, 1), (middle, 0)] {
+        let mut map = v15::CustomHashMap::with_strategy(v15::Modulus::PowerOfTwo, key_hash);
+        map.get_mut(first).add_temp(123, first);
+        map.get_mut(second).add_temp(-45, second);
+        assert_eq!(map.probe(second).1, expected_chain, "{:?}", key_hash);
+    }
+    // positions past the end of a short name sample zeros instead of panicking
+    let far = v15::CustomHashMap::with_strategy(v15::Modulus::PowerOfTwo, v15::KeyHash::SampledAt(v15::KeySampling { positions: [0, 90, 127, -128, -100, -1] }));
+    far.slot_index(b"Abc");
+
+    assert_eq!(v15::KeySampling::parse("0,1,2,-3,-2,-1"), Some(v15::KeySampling::default()));
+    for bad in ["", "0,1,2", "0,1,2,3,4,5,6", "0,1,x,3,4,5", "0,1,2,3,4,200"] {
+        assert_eq!(v15::KeySampling::parse(bad), None, "{}", bad);
+    }
+    println!("key sampling check passed");
+}
+
+// segments several SCAN_BUF_SIZE reads long, split at an arbitrary line, have to see every line
+// exactly once: the per-line sums over both segments must add up to the ones of the whole file
+pub fn test_multi_read_segment() {
+    let path = temp_path("multi_read_segment.txt");
+    let block = "Hamburg;12.0\nAbha;-3.5\nSan Francisco de Macorís;7.7\nA;0.1\n".repeat(10_000);
+    let repeats = 3 * v15::SCAN_BUF_SIZE / block.len() + 1;
+    std::fs::write(&path, block.repeat(repeats)).unwrap();
+    let file = File::open(&path).unwrap();
+    let file_len = file.metadata().unwrap().len() as usize;
+    let expected_lines = 4 * 10_000 * repeats;
+
+    let count = |start: usize, end: usize| {
+        let (mut lines, mut total) = (0, 0i64);
+        let _ = v15::scan_segment_with(&file, start, end, None, |_, temp_slice| {
+            lines += 1;
+            total += v15::parse_temp(temp_slice) as i64;
+            std::ops::ControlFlow::Continue(())
+        });
+        (lines, total)
+    };
+    let whole = count(0, file_len);
+    assert_eq!(whole, (expected_lines, (120 - 35 + 77 + 1) * 10_000 * repeats as i64));
+
+    let splits = v15::find_segment_splits(&file, 2);
+    assert!(splits[0].1 - splits[0].0 > v15::SCAN_BUF_SIZE);
+    let (first, second) = (count(splits[0].0, splits[0].1), count(splits[1].0, splits[1].1));
+    assert_eq!((first.0 + second.0, first.1 + second.1), whole);
+    println!("multi read segment check passed ({} lines)", expected_lines);
+}
+
+// records every write so the test can check the output never goes out as one big buffer
+struct RecordingSink {
+    bytes: Vec<u8>,
+    largest_write: usize,
+}
+
+impl std::io::Write for RecordingSink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.largest_write = self.largest_write.max(buf.len());
+        self.bytes.extend_from_slice(buf);
+        return Ok(buf.len());
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        return Ok(());
+    }
+}
+
+// 50,000 synthetic stations, more than a CustomHashMap table holds, streamed into a sink: every
+// entry shows up once, in format_output's order, and no single write is bigger than an entry.
+// On a real file the streamed output matches run byte for byte
+pub fn test_streamed_output() {
+    const NUM_STATIONS: usize = 50_000;
+    // shuffled so the sort has something to do, plus names where name order and format_output's
+    // "name=" order disagree
+    let mut names: Vec<Vec<u8>> = (0..NUM_STATIONS - 2).map(|i| format!("Station {}", (i * 7_919) % (NUM_STATIONS - 2)).into_bytes()).collect();
+    names.extend([b"Ab".to_vec(), b"Ab ".to_vec()]);
+    let stations: Vec<_> = names.iter().enumerate().map(|(i, name)| v15::StationData::from_samples(name, &[(i % 1_999) as i32 - 999])).collect();
+
+    let mut sink = RecordingSink { bytes: Vec::new(), largest_write: 0 };
+    v15::write_stations_streamed(stations.iter(), true, 1, &mut sink).unwrap();
+    assert!(sink.largest_write < 64, "largest write {} bytes", sink.largest_write);
+
+    let output = String::from_utf8(sink.bytes).unwrap();
+    let entries: Vec<_> = output.strip_prefix('{').unwrap().strip_suffix('}').unwrap().split(", ").collect();
+    assert_eq!(entries.len(), NUM_STATIONS);
+    assert!(entries.windows(2).all(|pair| pair[0] < pair[1]));
+    assert_eq!(entries.iter().position(|entry| entry.starts_with("Ab =")), Some(0));
+    assert!(entries[1].starts_with("Ab="));
+
+    let mut out = Vec::new();
+    v15::run_streamed(DEMO_MEASUREMENTS_PATH, &mut out).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), v15::run(DEMO_MEASUREMENTS_PATH));
+
+    println!("streamed output check passed ({} entries)", entries.len());
+}
+
+// the checks above that only need fixtures they write themselves (or the checked in demo files),
+// so cargo test runs them. The ones reading measurements.txt, the benchmarks and the dhat check
+// are still only called by hand from main
+#[cfg(test)]
+mod tests {
+    // v11-v14 keep their map in a stack array, which in a debug build doesn't fit the 2 MiB stack
+    // cargo gives each test thread (see v14::run_with_stack_size)
+    fn on_big_stack(check: fn()) {
+        let worker = std::thread::Builder::new().stack_size(32 * 1024 * 1024).spawn(check).unwrap();
+        worker.join().unwrap();
+    }
+
+    #[test]
+    fn mean_precision() {
+        super::test_mean_precision();
+    }
+    #[test]
+    fn ascii_hint() {
+        super::test_ascii_hint();
+    }
+    #[test]
+    fn diff_stations() {
+        super::test_diff_stations();
+    }
+    #[test]
+    fn find_char_agreement() {
+        super::test_find_char_agreement();
+    }
+    #[test]
+    fn snap_to_char_boundary() {
+        super::test_snap_to_char_boundary();
+    }
+    #[test]
+    fn scan_line() {
+        super::test_scan_line();
+    }
+    #[test]
+    fn accumulate_into() {
+        super::test_accumulate_into();
+    }
+    #[test]
+    fn detect_format() {
+        super::test_detect_format();
+    }
+    #[test]
+    fn temp_overflow() {
+        super::test_temp_overflow();
+    }
+    #[test]
+    fn zero_count_station() {
+        super::test_zero_count_station();
+    }
+    #[test]
+    fn carry_guard() {
+        on_big_stack(super::test_carry_guard);
+    }
+    #[test]
+    fn multi_format() {
+        super::test_multi_format();
+    }
+    #[test]
+    fn adaptive_segments() {
+        super::test_adaptive_segments();
+    }
+    #[test]
+    fn from_samples() {
+        super::test_from_samples();
+    }
+    #[test]
+    fn merge_serialized() {
+        super::test_merge_serialized();
+    }
+    #[test]
+    fn stream_malformed() {
+        super::test_stream_malformed();
+    }
+    #[test]
+    fn stream_read_error() {
+        super::test_stream_read_error();
+    }
+    #[test]
+    fn name_length() {
+        super::test_name_length();
+    }
+    #[test]
+    fn decimal_comma() {
+        super::test_decimal_comma();
+    }
+    #[test]
+    fn summary_stats() {
+        super::test_summary_stats();
+    }
+    #[test]
+    fn near_duplicates() {
+        super::test_near_duplicates();
+    }
+    #[test]
+    fn chunk_boundary_line() {
+        super::test_chunk_boundary_line();
+    }
+    #[test]
+    fn run_config() {
+        super::test_run_config();
+    }
+    #[test]
+    fn kway_merge() {
+        super::test_kway_merge();
+    }
+    #[test]
+    fn seed_collisions() {
+        super::test_seed_collisions();
+    }
+    #[test]
+    fn windowed() {
+        super::test_windowed();
+    }
+    #[test]
+    fn maps_equal() {
+        super::test_maps_equal();
+    }
+    #[test]
+    fn split_separator() {
+        super::test_split_separator();
+    }
+    #[test]
+    fn unsorted_output() {
+        super::test_unsorted_output();
+    }
+    #[test]
+    fn more_segments_than_lines() {
+        super::test_more_segments_than_lines();
+    }
+    #[test]
+    fn input_unit() {
+        super::test_input_unit();
+    }
+    #[test]
+    fn query_station() {
+        super::test_query_station();
+    }
+    #[test]
+    fn shared_file_reads() {
+        super::test_shared_file_reads();
+    }
+    #[test]
+    fn histogram_buckets() {
+        super::test_histogram_buckets();
+    }
+    #[test]
+    fn demo_fixture() {
+        on_big_stack(super::test_demo_fixture);
+    }
+    #[test]
+    fn interim_snapshots() {
+        super::test_interim_snapshots();
+    }
+    #[test]
+    fn find_char_padded() {
+        on_big_stack(super::test_find_char_padded);
+    }
+    #[test]
+    fn display_name() {
+        super::test_display_name();
+    }
+    #[test]
+    fn zero_total_mean() {
+        on_big_stack(super::test_zero_total_mean);
+    }
+    #[test]
+    fn allowlist() {
+        super::test_allowlist();
+    }
+    #[test]
+    fn exact_mean_formatting() {
+        super::test_exact_mean_formatting();
+    }
+    #[test]
+    fn open_precheck() {
+        super::test_open_precheck();
+    }
+    #[test]
+    fn collapse_runs() {
+        super::test_collapse_runs();
+    }
+    #[test]
+    fn sorted_input() {
+        super::test_sorted_input();
+    }
+    #[test]
+    fn grouped_output() {
+        super::test_grouped_output();
+    }
+    #[test]
+    fn natural_sort() {
+        super::test_natural_sort();
+    }
+    #[test]
+    fn v16_worker_idle() {
+        super::test_v16_worker_idle();
+    }
+    #[test]
+    fn merge_by_name() {
+        super::test_merge_by_name();
+    }
+    #[test]
+    fn cli_dispatch() {
+        super::test_cli_dispatch();
+    }
+    #[test]
+    fn missing_integer_digit() {
+        super::test_missing_integer_digit();
+    }
+    #[test]
+    fn output_checksum() {
+        super::test_output_checksum();
+    }
+    #[test]
+    fn wide_total() {
+        super::test_wide_total();
+    }
+    #[test]
+    fn colliding_names() {
+        on_big_stack(super::test_colliding_names);
+    }
+    #[test]
+    fn trailing_cr() {
+        super::test_trailing_cr();
+    }
+    #[test]
+    fn rotated() {
+        super::test_rotated();
+    }
+    #[test]
+    fn round_half_up() {
+        on_big_stack(super::test_round_half_up);
+    }
+    #[test]
+    fn span() {
+        super::test_span();
+    }
+    #[test]
+    fn v17() {
+        super::test_v17();
+    }
+    #[test]
+    fn mean_tie_table() {
+        super::test_mean_tie_table();
+    }
+    #[test]
+    fn precomputed_splits() {
+        super::test_precomputed_splits();
+    }
+    #[test]
+    fn long_line_at_split() {
+        super::test_long_line_at_split();
+    }
+    #[test]
+    fn v14_par_splits() {
+        super::test_v14_par_splits();
+    }
+    #[test]
+    fn key_sampling() {
+        super::test_key_sampling();
+    }
+    #[test]
+    fn multi_read_segment() {
+        super::test_multi_read_segment();
+    }
+    #[test]
+    fn streamed_output() {
+        super::test_streamed_output();
+    }
+    #[cfg(feature = "bgzip")]
+    #[test]
+    fn bgzip() {
+        super::test_bgzip();
+    }
+    #[cfg(all(unix, feature = "shm"))]
+    #[test]
+    fn shm_output() {
+        super::test_shm_output();
+    }
+}
@@ -0,0 +1,89 @@
+// Memory mapped scanning.
+//
+// Instead of read_at into per-thread buffers, the whole file is mapped and every thread scans its
+// segment straight out of the mapping, no copies and no chunk boundaries to handle. Mapping can
+// fail though (zero-length files are EINVAL on some platforms, some filesystems don't support it),
+// so this falls back to v15's positioned reads instead of panicking.
+
+use std::{fs::File, thread};
+
+use memchr::memchr;
+use memmap2::Mmap;
+
+use crate::v15::{BrcError, CustomHashMap, LineIter, RunOptions, aggregate_file, choose_num_segments, format_output, merge_maps, open_measurements, parse_temp};
+
+pub fn run_mmap(measurements_path: &str) -> Result<String, BrcError> {
+    return run_mmap_or_fallback(measurements_path, true);
+}
+
+// try_mmap = false skips straight to the fallback, as if mapping had failed
+pub(crate) fn run_mmap_or_fallback(measurements_path: &str, try_mmap: bool) -> Result<String, BrcError> {
+    let file = open_measurements(measurements_path)?;
+    if file.metadata()?.len() == 0 {
+        return Ok("{}".to_owned());
+    }
+
+    let mapped = match try_mmap {
+        true => map_file(&file),
+        false => None,
+    };
+    let Some(mapped) = mapped else {
+        let (map, _stats) = aggregate_file(measurements_path, &RunOptions::default())?;
+        return Ok(format_output(&map));
+    };
+
+    return Ok(format_output(&aggregate_bytes(&mapped)));
+}
+
+fn map_file(file: &File) -> Option<Mmap> {
+    // SAFETY: the mapping is only read, and like every other mode this assumes nobody modifies the
+    // measurements file while it's being processed
+    return unsafe { Mmap::map(file) }.ok();
+}
+
+// splits bytes into line aligned segments and scans them in parallel
+pub(crate) fn aggregate_bytes(bytes: &[u8]) -> CustomHashMap {
+    let splits = find_segment_splits(bytes, choose_num_segments(bytes.len()));
+
+    let maps = thread::scope(|s| {
+        let handles: Vec<_> = splits
+            .into_iter()
+            .map(|(start, end)| s.spawn(move || scan_bytes(&bytes[start..end])))
+            .collect();
+        return handles.into_iter().map(|h| h.join().unwrap()).collect::<Vec<_>>();
+    });
+    return merge_maps(maps);
+}
+
+// v15's find_segment_splits, but searching the mapped bytes instead of reading at each split.
+// Segments end just after a newline (or at the end of the file), a search start past the last
+// newline gives an empty segment
+pub(crate) fn find_segment_splits(bytes: &[u8], num_segments: usize) -> Vec<(usize, usize)> {
+    let mut splits = Vec::with_capacity(num_segments);
+    let mut start = 0;
+    for i in 1..num_segments {
+        let search_start = (i * bytes.len() / num_segments).max(start);
+        let end = memchr(b'\n', &bytes[search_start..]).map_or(bytes.len(), |j| search_start + j + 1);
+        splits.push((start, end));
+        start = end;
+    }
+    splits.push((start, bytes.len()));
+    return splits;
+}
+
+fn scan_bytes(bytes: &[u8]) -> CustomHashMap {
+    let mut map = CustomHashMap::new();
+    let mut lines = LineIter::new(bytes);
+    for (name_slice, temp_slice) in lines.by_ref() {
+        let temp = parse_temp(temp_slice);
+        map.get_mut(name_slice).add_temp(temp, name_slice);
+    }
+
+    // the last line of the file might not end with a newline
+    let rest = lines.remainder();
+    if let Some(semicolon_pos) = memchr(b';', rest) {
+        let temp = parse_temp(&rest[semicolon_pos+1..]);
+        map.get_mut(&rest[..semicolon_pos]).add_temp(temp, &rest[..semicolon_pos]);
+    }
+    return map;
+}
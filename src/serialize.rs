@@ -0,0 +1,100 @@
+// Compact binary form of a map's aggregates, for shipping partial results between machines.
+//
+// A distributed run looks like: shard the input file, run serialize_file on each shard on its
+// own worker, collect the bytes and combine them with merge_serialized.
+//
+// Layout (all little endian):
+//      - magic b"BRC1"
+//      - u32 number of stations
+//      - per station: u16 name length, name bytes, i32 min, i32 max, i64 total, u64 count
+
+use std::io::{Error, ErrorKind};
+
+use crate::v15::{BrcError, CustomHashMap, RunOptions, StationData, aggregate_file, format_output, merge_maps};
+
+const MAGIC: &[u8; 4] = b"BRC1";
+
+pub(crate) fn serialize_map(map: &CustomHashMap) -> Vec<u8> {
+    let stations: Vec<_> = map.stations().collect();
+
+    let mut bytes = Vec::with_capacity(8 + stations.len() * 64);
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&(stations.len() as u32).to_le_bytes());
+    for data in stations {
+        let name = data.name.as_deref().unwrap();
+        bytes.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(name);
+        bytes.extend_from_slice(&data.min_temp.to_le_bytes());
+        bytes.extend_from_slice(&data.max_temp.to_le_bytes());
+        bytes.extend_from_slice(&data.total.to_le_bytes());
+        bytes.extend_from_slice(&(data.count as u64).to_le_bytes());
+    }
+    return bytes;
+}
+
+// cursor over the serialized bytes, every read fails cleanly on truncated input
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.bytes.len() < n {
+            return None;
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        return Some(taken);
+    }
+    fn array<const N: usize>(&mut self) -> Option<[u8; N]> {
+        return self.take(N).map(|b| b.try_into().unwrap());
+    }
+}
+
+pub(crate) fn deserialize_map(bytes: &[u8]) -> Option<CustomHashMap> {
+    let mut reader = Reader { bytes };
+    if reader.take(4)? != MAGIC {
+        return None;
+    }
+    let num_stations = u32::from_le_bytes(reader.array()?);
+
+    let mut map = CustomHashMap::new();
+    for _ in 0..num_stations {
+        let name_len = u16::from_le_bytes(reader.array()?) as usize;
+        let name = reader.take(name_len)?.to_vec();
+        let data = StationData {
+            min_temp: i32::from_le_bytes(reader.array()?),
+            max_temp: i32::from_le_bytes(reader.array()?),
+            total: i64::from_le_bytes(reader.array()?),
+            // counts are u32 in memory, a bigger one can't have come from serialize_map
+            count: u32::try_from(u64::from_le_bytes(reader.array()?)).ok()?,
+            name: Some(name),
+        };
+        let name = data.name.as_deref().unwrap();
+        if name.is_empty() {
+            return None;
+        }
+        map.get_mut(name).merge_with(&data);
+    }
+    if !reader.bytes.is_empty() {
+        return None;
+    }
+    return Some(map);
+}
+
+// processes one shard and returns its serialized partial result
+pub fn serialize_file(measurements_path: &str) -> Result<Vec<u8>, BrcError> {
+    let (map, _stats) = aggregate_file(measurements_path, &RunOptions::default())?;
+    return Ok(serialize_map(&map));
+}
+
+// combines the serialized partial results of several workers into the final output
+pub fn merge_serialized(parts: &[&[u8]]) -> Result<String, BrcError> {
+    let maps = parts
+        .iter()
+        .enumerate()
+        .map(|(i, part)| deserialize_map(part).ok_or_else(|| BrcError::Io(Error::new(ErrorKind::InvalidData, format!("serialized part {} is malformed", i)))))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    return Ok(format_output(&merge_maps(maps)));
+}
@@ -0,0 +1,92 @@
+// Handing the output to another process through POSIX shared memory.
+//
+// The producer creates a named shm object (shm_open), sizes it to the canonical output
+// (ftruncate) and maps it (mmap) to write the output in. A consumer that knows the name opens and
+// maps the same object read-only. The object's size is exactly the output's length, so there's no
+// separate length header to agree on.
+//
+// The object outlives both processes until it's unlinked, so whoever is done with it last calls
+// ShmOutput::unlink or unlink_shm.
+
+use std::{ffi::CString, fs::File, io::{self, ErrorKind}, os::fd::{FromRawFd, OwnedFd}};
+
+use memmap2::{Mmap, MmapMut};
+
+use crate::v15::{BrcError, CustomHashMap, format_output};
+
+// the producer's writable mapping of the output
+pub struct ShmOutput {
+    name: CString,
+    mapping: MmapMut,
+}
+
+impl ShmOutput {
+    pub fn as_bytes(&self) -> &[u8] {
+        return &self.mapping;
+    }
+    pub fn name(&self) -> &str {
+        return self.name.to_str().unwrap();
+    }
+    // removes the name, the memory itself goes away once every mapping of it is gone
+    pub fn unlink(self) -> Result<(), BrcError> {
+        return unlink_shm(self.name());
+    }
+}
+
+// names are "/something" with no further slashes, the leading slash is added if missing
+fn shm_name(name: &str) -> Result<CString, BrcError> {
+    let name = if name.starts_with('/') { name.to_owned() } else { format!("/{}", name) };
+    if name.len() < 2 || name[1..].contains('/') {
+        return Err(BrcError::Io(io::Error::new(ErrorKind::InvalidInput, format!("invalid shm name \"{}\"", name))));
+    }
+    return CString::new(name).map_err(|e| BrcError::Io(io::Error::new(ErrorKind::InvalidInput, e)));
+}
+
+fn open_shm(name: &CString, flags: libc::c_int) -> Result<File, BrcError> {
+    // SAFETY: name is a valid nul terminated string, and the returned fd is checked before it's
+    // wrapped, after which the File is its only owner
+    let fd = unsafe { libc::shm_open(name.as_ptr(), flags, 0o600) };
+    if fd < 0 {
+        return Err(BrcError::Io(io::Error::last_os_error()));
+    }
+    return Ok(File::from(unsafe { OwnedFd::from_raw_fd(fd) }));
+}
+
+// creates (or replaces the contents of) the shm object `name` and writes map's canonical output
+// into it. The returned mapping keeps the memory alive in this process, the object itself stays
+// around for consumers until it's unlinked
+pub fn write_output_to_shm(map: &CustomHashMap, name: &str) -> Result<ShmOutput, BrcError> {
+    let name = shm_name(name)?;
+    let output = format_output(map);
+
+    let file = open_shm(&name, libc::O_CREAT | libc::O_RDWR)?;
+    // ftruncate, which also drops whatever a previous producer left in a reused object
+    file.set_len(0)?;
+    file.set_len(output.len() as u64)?;
+
+    // SAFETY: the object was just sized to the output, and like any shared memory this relies on
+    // nobody else resizing it while it's mapped
+    let mut mapping = unsafe { MmapMut::map_mut(&file) }?;
+    mapping.copy_from_slice(output.as_bytes());
+    mapping.flush()?;
+
+    return Ok(ShmOutput { name, mapping });
+}
+
+// the consumer side: maps the object read-only and copies the output out
+pub fn read_output_from_shm(name: &str) -> Result<String, BrcError> {
+    let name = shm_name(name)?;
+    let file = open_shm(&name, libc::O_RDONLY)?;
+    // SAFETY: see write_output_to_shm
+    let mapping = unsafe { Mmap::map(&file) }?;
+    return String::from_utf8(mapping.to_vec()).map_err(|e| BrcError::Io(io::Error::new(ErrorKind::InvalidData, e)));
+}
+
+pub fn unlink_shm(name: &str) -> Result<(), BrcError> {
+    let name = shm_name(name)?;
+    // SAFETY: name is a valid nul terminated string
+    if unsafe { libc::shm_unlink(name.as_ptr()) } < 0 {
+        return Err(BrcError::Io(io::Error::last_os_error()));
+    }
+    return Ok(());
+}
@@ -0,0 +1,164 @@
+// Approximate per-station quantiles using a merging t-digest.
+//
+// Unlike a fixed histogram, the sketch works on f64 values so it doesn't care about the
+// temperature scale (tenths, hundredths, ...). Memory is bounded by the compression factor:
+// a compressed sketch holds at most ~compression centroids no matter how many values it saw.
+// Sketches are mergeable, so each segment thread can build its own and they are combined at the end.
+
+use std::f64::consts::PI;
+
+use crate::v15::run_per_station;
+
+const DEFAULT_COMPRESSION: f64 = 100.0;
+
+#[derive(Debug, Clone, Copy)]
+struct Centroid {
+    mean: f64,
+    weight: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct QuantileSketch {
+    compression: f64,
+    centroids: Vec<Centroid>,
+    buffer: Vec<f64>,
+    count: f64,
+    min: f64,
+    max: f64,
+}
+
+impl QuantileSketch {
+    pub fn new() -> Self {
+        Self::with_compression(DEFAULT_COMPRESSION)
+    }
+    pub fn with_compression(compression: f64) -> Self {
+        Self {
+            compression,
+            centroids: Vec::new(),
+            buffer: Vec::with_capacity(Self::buffer_cap(compression)),
+            count: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+    fn buffer_cap(compression: f64) -> usize {
+        (compression as usize) * 4
+    }
+    #[inline(always)]
+    pub fn add(&mut self, value: f64) {
+        self.buffer.push(value);
+        self.count += 1.0;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        if self.buffer.len() >= Self::buffer_cap(self.compression) {
+            self.compress();
+        }
+    }
+    pub fn merge_with(&mut self, other: &QuantileSketch) {
+        if other.count == 0.0 {
+            return;
+        }
+        self.centroids.extend_from_slice(&other.centroids);
+        self.buffer.extend_from_slice(&other.buffer);
+        self.count += other.count;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.compress();
+    }
+    pub fn count(&self) -> u64 {
+        self.count as u64
+    }
+
+    // k1 scale function from the t-digest paper, keeps centroids small near the tails
+    fn k(&self, q: f64) -> f64 {
+        self.compression / (2.0 * PI) * (2.0 * q - 1.0).asin()
+    }
+    fn k_inv(&self, k: f64) -> f64 {
+        ((k * 2.0 * PI / self.compression).sin() + 1.0) / 2.0
+    }
+
+    fn compress(&mut self) {
+        if self.buffer.is_empty() && self.centroids.len() <= 1 {
+            return;
+        }
+
+        let mut all = std::mem::take(&mut self.centroids);
+        all.extend(self.buffer.drain(..).map(|v| Centroid { mean: v, weight: 1.0 }));
+        all.sort_by(|a, b| a.mean.total_cmp(&b.mean));
+
+        let total = self.count;
+        let mut merged: Vec<Centroid> = Vec::with_capacity(self.compression as usize);
+        let mut cum = 0.0;
+        let mut q_limit = self.k_inv(self.k(0.0) + 1.0) * total;
+
+        let mut curr = all[0];
+        for next in &all[1..] {
+            if cum + curr.weight + next.weight <= q_limit {
+                // fold next into the current centroid
+                curr.weight += next.weight;
+                curr.mean += (next.mean - curr.mean) * next.weight / curr.weight;
+            } else {
+                cum += curr.weight;
+                merged.push(curr);
+                q_limit = self.k_inv(self.k((cum / total).min(1.0)) + 1.0) * total;
+                curr = *next;
+            }
+        }
+        merged.push(curr);
+
+        self.centroids = merged;
+    }
+
+    // approximate q-th quantile (0.0 ..= 1.0), interpolating between centroid centers
+    pub fn quantile(&self, q: f64) -> f64 {
+        if self.count == 0.0 {
+            return f64::NAN;
+        }
+        if !self.buffer.is_empty() {
+            let mut compressed = self.clone();
+            compressed.compress();
+            return compressed.quantile(q);
+        }
+
+        let q = q.clamp(0.0, 1.0);
+        if q == 0.0 {
+            return self.min;
+        }
+        if q == 1.0 {
+            return self.max;
+        }
+
+        let target = q * self.count;
+        let mut cum = 0.0;
+        let mut prev_center = 0.0;
+        let mut prev_mean = self.min;
+        for c in &self.centroids {
+            let center = cum + c.weight / 2.0;
+            if target < center {
+                let span = center - prev_center;
+                let t = if span > 0.0 { (target - prev_center) / span } else { 0.0 };
+                return prev_mean + t * (c.mean - prev_mean);
+            }
+            cum += c.weight;
+            prev_center = center;
+            prev_mean = c.mean;
+        }
+
+        // past the last centroid center, interpolate towards the max
+        let span = self.count - prev_center;
+        let t = if span > 0.0 { (target - prev_center) / span } else { 1.0 };
+        return prev_mean + t * (self.max - prev_mean);
+    }
+}
+
+// scans the file in parallel and reports the requested quantiles of each station, formatted
+// like the normal output but with one value per quantile: {name=q0/q1/...}
+pub fn run_quantiles(measurements_path: &str, quantiles: &[f64]) -> String {
+    return run_per_station(
+        measurements_path,
+        QuantileSketch::new,
+        |sketch, temp| sketch.add(0.1 * temp as f64),
+        |merged, sketch| merged.merge_with(sketch),
+        |sketch| quantiles.iter().map(|q| format!("{:.1}", sketch.quantile(*q))).collect::<Vec<_>>().join("/"),
+    );
+}
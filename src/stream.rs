@@ -0,0 +1,221 @@
+// Single-threaded scanning of non-seekable input (sockets, pipes, ...).
+//
+// This is v14's BufReader + carry loop made generic over any Read. Since the input can't be
+// split into segments up front there's no parallelism here, but lines split across reads are
+// stitched back together through the carry buffer.
+
+use std::{io::{self, BufRead, BufReader, ErrorKind, Read}, net::TcpListener, sync::mpsc::Receiver};
+
+use memchr::{memchr, memmem};
+
+use crate::v15::{BrcError, CustomHashMap, find_char, format_output, parse_temp};
+
+pub fn run_stream<R: Read>(reader: R) -> Result<String, BrcError> {
+    let mut map = CustomHashMap::new();
+    scan_reader(reader, &mut map)?;
+    return Ok(format_output(&map));
+}
+
+// listens on addr, accepts a single connection and processes everything sent on it until the
+// peer closes the connection
+pub fn run_tcp(addr: &str) -> Result<String, BrcError> {
+    let listener = TcpListener::bind(addr)?;
+    let (stream, _peer) = listener.accept()?;
+    return run_stream(stream);
+}
+
+// aggregates chunks of measurement data sent over a channel until every sender is dropped.
+// Chunks don't need to line up with lines, a line split across two chunks is stitched back
+// together by the carry logic like any other read boundary
+pub fn run_from_channel(rx: Receiver<Vec<u8>>) -> Result<String, BrcError> {
+    return run_stream(ChannelReader { rx, chunk: Vec::new(), pos: 0 });
+}
+
+// adapts a channel of byte chunks into a Read so it can go through scan_reader
+struct ChannelReader {
+    rx: Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos >= self.chunk.len() {
+            match self.rx.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                // all senders dropped, that's our EOF
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = out.len().min(self.chunk.len() - self.pos);
+        out[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        return Ok(n);
+    }
+}
+
+// read errors are passed up, except Interrupted, where nothing was read and fill_buf is retried
+pub(crate) fn scan_reader<R: Read>(reader: R, map: &mut CustomHashMap) -> io::Result<()> {
+    let mut buf_reader = BufReader::with_capacity(16 * 16 * 1024, reader);
+    let mut carry = Vec::with_capacity(256);
+
+    loop {
+        let buf_len;
+        {
+            // get a direct reference to the next chunk from the reader
+            let buf = match buf_reader.fill_buf() {
+                Ok(buf) => buf,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            buf_len = buf.len();
+
+            // if buf is empty, we've reached the end so break
+            if buf.is_empty() {
+                // the last line might not end with a newline
+                if !carry.is_empty() {
+                    process_line(&carry, map);
+                }
+                break;
+            }
+
+            let mut line_start = buf_len;
+
+            // first finish off the carry (if it exists). Reads from a socket can be tiny, so the
+            // rest of the carried line isn't guaranteed to be in this chunk
+            if !carry.is_empty() {
+                match memchr(b'\n', buf) {
+                    Some(newline_pos) => {
+                        carry.extend_from_slice(&buf[..newline_pos]);
+                        process_line(&carry, map);
+                        carry.clear();
+                        line_start = newline_pos + 1;
+                    }
+                    None => carry.extend_from_slice(buf),
+                }
+            } else {
+                line_start = 0;
+            }
+
+            // main line reading loop
+            while line_start < buf_len {
+                let slice = &buf[line_start..];
+                if let Some(newline_pos) = find_char(slice, b'\n') {
+                    process_line(&slice[..newline_pos], map);
+                    line_start += newline_pos + 1;
+                } else {
+                    break;
+                }
+            }
+
+            // put the leftover in carry
+            if line_start < buf_len {
+                carry.extend_from_slice(&buf[line_start..]);
+            }
+        }
+
+        buf_reader.consume(buf_len);
+    }
+    return Ok(());
+}
+
+pub fn run_stream_with_separator<R: Read>(reader: R, sep: &[u8]) -> Result<String, BrcError> {
+    let mut map = CustomHashMap::new();
+    scan_reader_with_separator(reader, sep, &mut map)?;
+    return Ok(format_output(&map));
+}
+
+// same as scan_reader, but records are terminated by sep instead of a single \n. A multibyte sep
+// can itself be split across two reads, so the carry may end with the first few bytes of a sep:
+// before extending the carry we check whether its last (up to sep.len()-1) bytes plus the start
+// of the new buf complete a separator
+pub(crate) fn scan_reader_with_separator<R: Read>(reader: R, sep: &[u8], map: &mut CustomHashMap) -> io::Result<()> {
+    assert!(!sep.is_empty(), "record separator must not be empty");
+    let finder = memmem::Finder::new(sep);
+    let mut buf_reader = BufReader::with_capacity(16 * 16 * 1024, reader);
+    let mut carry = Vec::with_capacity(256);
+
+    loop {
+        let buf_len;
+        {
+            let buf = match buf_reader.fill_buf() {
+                Ok(buf) => buf,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            buf_len = buf.len();
+
+            if buf.is_empty() {
+                if !carry.is_empty() {
+                    process_line(&carry, map);
+                }
+                break;
+            }
+
+            let mut line_start = 0;
+
+            if !carry.is_empty() {
+                // the longest split first, that's the separator starting earliest in the carry
+                let split = (1..sep.len()).rev().find(|&k| {
+                    carry.ends_with(&sep[..k]) && buf.starts_with(&sep[k..])
+                });
+                if let Some(k) = split {
+                    carry.truncate(carry.len() - k);
+                    if !carry.is_empty() {
+                        process_line(&carry, map);
+                    }
+                    carry.clear();
+                    line_start = sep.len() - k;
+                } else {
+                    match finder.find(buf) {
+                        Some(sep_pos) => {
+                            carry.extend_from_slice(&buf[..sep_pos]);
+                            process_line(&carry, map);
+                            carry.clear();
+                            line_start = sep_pos + sep.len();
+                        }
+                        None => {
+                            carry.extend_from_slice(buf);
+                            line_start = buf_len;
+                        }
+                    }
+                }
+            }
+
+            while line_start < buf_len {
+                match finder.find(&buf[line_start..]) {
+                    Some(sep_pos) => {
+                        if sep_pos > 0 {
+                            process_line(&buf[line_start..line_start + sep_pos], map);
+                        }
+                        line_start += sep_pos + sep.len();
+                    }
+                    None => break,
+                }
+            }
+
+            // the leftover may end in a partial separator, which the check above picks up
+            if line_start < buf_len {
+                carry.extend_from_slice(&buf[line_start..]);
+            }
+        }
+
+        buf_reader.consume(buf_len);
+    }
+    return Ok(());
+}
+
+#[inline(always)]
+fn process_line(line: &[u8], map: &mut CustomHashMap) {
+    // no ; means no station (blank lines included), skip it like v15's scan does
+    let Some(semicolon_pos) = memchr(b';', line) else {
+        return;
+    };
+    let name_slice = &line[..semicolon_pos];
+    let temp_slice = &line[semicolon_pos+1..];
+    let temp = parse_temp(temp_slice);
+    map.get_mut(name_slice).add_temp(temp, name_slice);
+}
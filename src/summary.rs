@@ -0,0 +1,145 @@
+// Summaries over the per-station results, for follow-up analysis once a run is done.
+
+use std::collections::HashMap;
+
+use crate::v15::{BrcError, CustomHashMap, RunOptions, StationData, aggregate_file};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GlobalSummary {
+    // (station name, mean temperature), None for an empty map
+    pub coldest_mean: Option<(String, f64)>,
+    pub warmest_mean: Option<(String, f64)>,
+    // plain average of the station means, every station weighs the same regardless of how many
+    // readings it has
+    pub average_of_means: Option<f64>,
+    pub num_stations: usize,
+}
+
+// means are in degrees, ties go to the station that sorts first by name
+pub fn summary_stats(map: &CustomHashMap) -> GlobalSummary {
+    let mut stations: Vec<_> = map
+        .stations()
+        .map(|data| (String::from_utf8_lossy(data.name.as_deref().unwrap()).into_owned(), 0.1 * data.total as f64 / data.count as f64))
+        .collect();
+    stations.sort_by(|a, b| a.0.as_bytes().cmp(b.0.as_bytes()));
+
+    let num_stations = stations.len();
+    let average_of_means = match num_stations {
+        0 => None,
+        _ => Some(stations.iter().map(|(_, mean)| mean).sum::<f64>() / num_stations as f64),
+    };
+    // min_by/max_by keep the first/last of equal elements, reversing makes max_by keep the first
+    let coldest_mean = stations.iter().min_by(|a, b| a.1.total_cmp(&b.1)).cloned();
+    let warmest_mean = stations.iter().rev().max_by(|a, b| a.1.total_cmp(&b.1)).cloned();
+
+    return GlobalSummary { coldest_mean, warmest_mean, average_of_means, num_stations };
+}
+
+// (station name, max - min in degrees) for every station, most variable first. Ties go to the
+// station that sorts first by name
+pub fn run_by_span(measurements_path: &str) -> Vec<(String, f64)> {
+    let (map, _stats) = aggregate_file(measurements_path, &RunOptions::default()).unwrap_or_else(|e| panic!("{}", e));
+    return stations_by_span(&map);
+}
+
+pub fn stations_by_span(map: &CustomHashMap) -> Vec<(String, f64)> {
+    // sorted on the span in whole tenths, the f64 is only for the caller
+    let mut stations: Vec<_> = map
+        .stations()
+        .map(|data| (data.name.as_deref().unwrap(), data.max_temp - data.min_temp))
+        .collect();
+    stations.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+
+    return stations
+        .into_iter()
+        .map(|(name, span)| (String::from_utf8_lossy(name).into_owned(), 0.1 * span as f64))
+        .collect();
+}
+
+// scans the file and reports groups of station names that only differ in leading/trailing
+// whitespace or ASCII case, e.g. "Tokyo " and "tokyo" next to "Tokyo". Those end up as separate
+// stations, which is usually a data problem rather than intended
+pub fn report_near_duplicates(measurements_path: &str) -> Result<Vec<Vec<String>>, BrcError> {
+    let (map, _stats) = aggregate_file(measurements_path, &RunOptions::default())?;
+    return Ok(near_duplicate_names(&map));
+}
+
+// every group has at least two names, names within a group and the groups themselves are sorted
+pub fn near_duplicate_names(map: &CustomHashMap) -> Vec<Vec<String>> {
+    let mut groups: HashMap<Vec<u8>, Vec<String>> = HashMap::new();
+    for data in map.stations() {
+        let name = data.name.as_deref().unwrap();
+        let key = name.trim_ascii().to_ascii_lowercase();
+        groups.entry(key).or_default().push(String::from_utf8_lossy(name).into_owned());
+    }
+
+    let mut near_duplicates: Vec<_> = groups
+        .into_values()
+        .filter(|names| names.len() > 1)
+        .map(|mut names| {
+            names.sort();
+            names
+        })
+        .collect();
+    near_duplicates.sort();
+    return near_duplicates;
+}
+
+// one station's final numbers, in degrees
+#[derive(Debug, Clone, PartialEq)]
+pub struct StationSummary {
+    pub name: String,
+    pub min: f64,
+    pub mean: f64,
+    pub max: f64,
+    pub count: u64,
+}
+
+// the stations of map sorted by name
+pub fn station_summaries(map: &CustomHashMap) -> Vec<StationSummary> {
+    let mut summaries: Vec<_> = map
+        .stations()
+        .map(summarize)
+        .collect();
+    summaries.sort_by(|a, b| a.name.as_bytes().cmp(b.name.as_bytes()));
+    return summaries;
+}
+
+// the numbers for a single station, None if it never appears in the file. The whole file still
+// has to be scanned, this only saves formatting every other station
+pub fn query_station(measurements_path: &str, name: &[u8]) -> Result<Option<StationSummary>, BrcError> {
+    let (map, _stats) = aggregate_file(measurements_path, &RunOptions::default())?;
+    return Ok(map.stations().find(|data| data.name.as_deref() == Some(name)).map(summarize));
+}
+
+fn summarize(data: &StationData) -> StationSummary {
+    return StationSummary {
+        name: String::from_utf8_lossy(data.name.as_deref().unwrap()).into_owned(),
+        min: 0.1 * data.min_temp as f64,
+        mean: 0.1 * data.total as f64 / data.count as f64,
+        max: 0.1 * data.max_temp as f64,
+        count: data.count as u64,
+    };
+}
+
+// struct-of-arrays form of a list of summaries, index i of every column is the same station
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Columnar {
+    pub names: Vec<String>,
+    pub mins: Vec<f64>,
+    pub means: Vec<f64>,
+    pub maxes: Vec<f64>,
+    pub counts: Vec<u64>,
+}
+
+pub fn results_columnar(summaries: &[StationSummary]) -> Columnar {
+    let mut columnar = Columnar::default();
+    for summary in summaries {
+        columnar.names.push(summary.name.clone());
+        columnar.mins.push(summary.min);
+        columnar.means.push(summary.mean);
+        columnar.maxes.push(summary.max);
+        columnar.counts.push(summary.count);
+    }
+    return columnar;
+}
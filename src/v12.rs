@@ -13,6 +13,8 @@
 
 use std::{fs::File, i32, os::unix::fs::FileExt};
 
+use crate::v15::{format_fixed, mean_half_up};
+
 pub fn run(measurements_path: &str) -> String {
     let measurements_file = std::fs::File::open(measurements_path).unwrap();
 
@@ -151,7 +153,7 @@ fn format_output(map: &CustomHashMap) -> String {
 struct StationData {
     min_temp: i32,
     max_temp: i32,
-    total: i32,
+    total: i64,
     count: u32,
     name: Option<Vec<u8>>,
 }
@@ -169,18 +171,18 @@ impl StationData {
     pub fn add_temp(&mut self, temp: i32, name: &[u8]) {
         self.min_temp = self.min_temp.min(temp);
         self.max_temp = self.max_temp.max(temp);
-        self.total += temp;
+        self.total += temp as i64;
         self.count += 1;
         if self.name.is_none() {
             self.name = Some(name.to_vec());
         }
     }
     pub fn format_data_point(&self) -> String {
-        return format!("{}={:.1}/{:.1}/{:.1}", 
+        return format!("{}={}/{}/{}", 
             String::from_utf8(self.name.clone().unwrap()).unwrap(), 
-            0.1 * self.min_temp as f32, 
-            0.1 * self.total as f32 / self.count as f32, 
-            0.1 * self.max_temp as f32
+            format_fixed(self.min_temp as i64, 1), 
+            format_fixed(mean_half_up(self.total, self.count), 1), 
+            format_fixed(self.max_temp as i64, 1)
         );
     }
 }
@@ -198,7 +200,13 @@ impl CustomHashMap {
     pub fn get_mut(&mut self, key: &[u8]) -> &mut StationData {
         let u64_key = get_u64_key(key);
         let hashed_key = mix64(u64_key).wrapping_mul(384); // 384 is a magic seed
-        let index = hashed_key as usize % self.backing.len();
+        let mut index = hashed_key as usize % self.backing.len();
+        let mut chain = 0;
+        while let Some(name) = &self.backing[index].name && name.as_slice() != key {
+            chain += 1;
+            assert!(chain < self.backing.len(), "hash table full, more than {} distinct station names", self.backing.len());
+            index = (index + 1) % self.backing.len();
+        }
         return &mut self.backing[index];
     }
 }
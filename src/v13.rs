@@ -15,6 +15,8 @@
 
 use std::{fs::File, i32, io::{BufRead, BufReader}};
 
+use crate::v15::{format_fixed, mean_half_up};
+
 pub fn run(measurements_path: &str) -> String {
     let measurements_file = std::fs::File::open(measurements_path).unwrap();
 
@@ -28,6 +30,8 @@ pub fn run(measurements_path: &str) -> String {
 
 fn custom_scan_file(mut buf_reader: BufReader<File>, map: &mut CustomHashMap) {
     let mut carry = Vec::with_capacity(256);
+    // position of the ; within carry, if the line was split after its ;
+    let mut carry_semicolon: Option<usize> = None;
 
     loop {
         let buf_len;
@@ -42,7 +46,7 @@ fn custom_scan_file(mut buf_reader: BufReader<File>, map: &mut CustomHashMap) {
             if buf.is_empty() {
                 // still need to check carry if its not empty
                 if !carry.is_empty() {
-                    let semicolon_pos = memchr::memchr(b';', &carry).unwrap();
+                    let semicolon_pos = carry_semicolon.unwrap_or_else(|| memchr::memchr(b';', &carry).unwrap());
                     let name_slice = &carry[..semicolon_pos];
                     let temp_slice = &carry[semicolon_pos+1..];
                     let temp = parse_temp(temp_slice);
@@ -65,13 +69,19 @@ fn custom_scan_file(mut buf_reader: BufReader<File>, map: &mut CustomHashMap) {
                 let semicolon_pos;
                 let i = iter.next().unwrap();
 
-                if buf[i] == b';' {
+                if let Some(carry_semicolon_pos) = carry_semicolon {
+                    // the ; was already found in the previous buf, this first hit is the newline
+                    carry.extend_from_slice(&buf[..i]);
+                    semicolon_pos = carry_semicolon_pos;
+                    line_start = i + 1;
+                } else if buf[i] == b';' {
                     let j = iter.next().unwrap();
                     let extra = carry.len();
                     carry.extend_from_slice(&buf[..j]);
                     semicolon_pos = i + extra;
                     line_start = j + 1;
                 } else {
+                    // fallback, well formed input always hits one of the branches above
                     carry.extend_from_slice(&buf[..i]);
                     semicolon_pos = memchr::memchr(b';', &carry).unwrap();
                     line_start = i + 1;
@@ -83,6 +93,7 @@ fn custom_scan_file(mut buf_reader: BufReader<File>, map: &mut CustomHashMap) {
                 map.get_mut(name_slice).add_temp(temp, name_slice);
 
                 carry.clear();
+                carry_semicolon = None;
             }
 
             // main line reading loop
@@ -93,6 +104,9 @@ fn custom_scan_file(mut buf_reader: BufReader<File>, map: &mut CustomHashMap) {
                     let temp = parse_temp(temp_slice);
                     map.get_mut(name_slice).add_temp(temp, name_slice);
                     line_start = endline_pos + 1;
+                } else {
+                    // the line is split after its ;, remember where it was relative to the carry
+                    carry_semicolon = Some(semicolon_pos - line_start);
                 }
             }
 
@@ -141,7 +155,7 @@ fn format_output(map: &CustomHashMap) -> String {
 struct StationData {
     min_temp: i32,
     max_temp: i32,
-    total: i32,
+    total: i64,
     count: u32,
     name: Option<Vec<u8>>,
 }
@@ -159,18 +173,18 @@ impl StationData {
     pub fn add_temp(&mut self, temp: i32, name: &[u8]) {
         self.min_temp = self.min_temp.min(temp);
         self.max_temp = self.max_temp.max(temp);
-        self.total += temp;
+        self.total += temp as i64;
         self.count += 1;
         if self.name.is_none() {
             self.name = Some(name.to_vec());
         }
     }
     pub fn format_data_point(&self) -> String {
-        return format!("{}={:.1}/{:.1}/{:.1}", 
+        return format!("{}={}/{}/{}", 
             String::from_utf8(self.name.clone().unwrap()).unwrap(), 
-            0.1 * self.min_temp as f32, 
-            0.1 * self.total as f32 / self.count as f32, 
-            0.1 * self.max_temp as f32
+            format_fixed(self.min_temp as i64, 1), 
+            format_fixed(mean_half_up(self.total, self.count), 1), 
+            format_fixed(self.max_temp as i64, 1)
         );
     }
 }
@@ -188,7 +202,13 @@ impl CustomHashMap {
     pub fn get_mut(&mut self, key: &[u8]) -> &mut StationData {
         let u64_key = get_u64_key(key);
         let hashed_key = mix64(u64_key).wrapping_mul(384); // 384 is a magic seed
-        let index = hashed_key as usize % self.backing.len();
+        let mut index = hashed_key as usize % self.backing.len();
+        let mut chain = 0;
+        while let Some(name) = &self.backing[index].name && name.as_slice() != key {
+            chain += 1;
+            assert!(chain < self.backing.len(), "hash table full, more than {} distinct station names", self.backing.len());
+            index = (index + 1) % self.backing.len();
+        }
         return &mut self.backing[index];
     }
 }
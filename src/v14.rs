@@ -11,22 +11,53 @@
 //      - SIMD is awesome
 
 
-use std::{fs::File, i32, io::{BufRead, BufReader}, simd::{Simd, cmp::SimdPartialEq, u8x16}};
+use std::{fs::File, i32, io::{BufRead, BufReader}, simd::{Simd, cmp::SimdPartialEq, u8x16}, thread};
 
-use memchr::memchr;
+use crate::v15::{BrcError, format_fixed, mean_half_up};
+
+// the longest valid line: 100 byte name, ';', "-99.9" and '\n'
+const MAX_LINE_LEN: usize = 107;
 
 pub fn run(measurements_path: &str) -> String {
-    let measurements_file = std::fs::File::open(measurements_path).unwrap();
+    return run_with_buffer_size(measurements_path, 16 * 16 * 1024).unwrap_or_else(|e| panic!("{}", e));
+}
+
+// like run but with a configurable BufReader capacity. Errors if a line gets carried across reads
+// for longer than any valid line could be, instead of growing carry without bound
+pub fn run_with_buffer_size(measurements_path: &str, buffer_size: usize) -> Result<String, BrcError> {
+    let measurements_file = std::fs::File::open(measurements_path)?;
 
-    let buf_reader = BufReader::with_capacity(16 * 16 * 1024, measurements_file);
+    let buf_reader = BufReader::with_capacity(buffer_size, measurements_file);
     let mut map = CustomHashMap::new();
 
-    custom_scan_file(buf_reader, &mut map);
+    custom_scan_file(buf_reader, &mut map)?;
+
+    return Ok(format_output(&map));
+}
+
+// for machines where v16's 128 MiB of read buffers is too much: one 256 KiB read buffer, a carry
+// of at most one line, and the map, which lives on the stack. Peak heap use is that buffer plus
+// the station names and the output, no matter how big the file is. Single threaded, so expect
+// v14 speeds
+pub fn run_low_memory(measurements_path: &str) -> Result<String, BrcError> {
+    const LOW_MEMORY_BUFFER_SIZE: usize = 256 * 1024;
+    return run_with_buffer_size(measurements_path, LOW_MEMORY_BUFFER_SIZE);
+}
 
-    return format_output(&map);
+// CustomHashMap here is a stack array (kept there for cache reasons), which together with the
+// temporaries core::array::from_fn creates in debug builds can overflow a default sized thread
+// stack. This runs the scan on a worker thread with an explicitly sized stack instead
+pub fn run_with_stack_size(measurements_path: &str, stack_size: usize) -> String {
+    let path = measurements_path.to_owned();
+    let worker = thread::Builder::new()
+        .name("v14-worker".to_owned())
+        .stack_size(stack_size)
+        .spawn(move || run(&path))
+        .unwrap();
+    return worker.join().unwrap();
 }
 
-fn custom_scan_file(mut buf_reader: BufReader<File>, map: &mut CustomHashMap) {
+fn custom_scan_file(mut buf_reader: BufReader<File>, map: &mut CustomHashMap) -> Result<(), BrcError> {
     let mut carry = Vec::with_capacity(256);
 
     loop {
@@ -34,7 +65,7 @@ fn custom_scan_file(mut buf_reader: BufReader<File>, map: &mut CustomHashMap) {
         {
             // println!("SCANNING CHUNK");
             // get a direct reference to the next chunk from the reader
-            let buf = buf_reader.fill_buf().unwrap();
+            let buf = buf_reader.fill_buf()?;
             buf_len = buf.len();
             // println!("buf_len: {}", buf.len());
 
@@ -55,17 +86,22 @@ fn custom_scan_file(mut buf_reader: BufReader<File>, map: &mut CustomHashMap) {
 
             // first deal with carry (if it exists)
             if !carry.is_empty() {
-                let newline_pos = buf.iter().position(|c| *c == b'\n').unwrap();
-                carry.extend_from_slice(&buf[..newline_pos]);
-                let semicolon_pos = carry.iter().position(|c| *c == b';').unwrap();
+                if let Some(newline_pos) = buf.iter().position(|c| *c == b'\n') {
+                    carry.extend_from_slice(&buf[..newline_pos]);
+                    let semicolon_pos = carry.iter().position(|c| *c == b';').unwrap();
 
-                let name_slice = &carry[..semicolon_pos];
-                let temp_slice = &carry[semicolon_pos+1..];
-                let temp = parse_temp(temp_slice);
-                map.get_mut(name_slice).add_temp(temp, name_slice);
+                    let name_slice = &carry[..semicolon_pos];
+                    let temp_slice = &carry[semicolon_pos+1..];
+                    let temp = parse_temp(temp_slice);
+                    map.get_mut(name_slice).add_temp(temp, name_slice);
 
-                carry.clear();
-                line_start = newline_pos + 1;
+                    carry.clear();
+                    line_start = newline_pos + 1;
+                } else {
+                    // the buffer is smaller than the rest of the line, keep carrying
+                    carry.extend_from_slice(buf);
+                    line_start = buf_len;
+                }
             }
 
             // main line reading loop
@@ -89,13 +125,21 @@ fn custom_scan_file(mut buf_reader: BufReader<File>, map: &mut CustomHashMap) {
             if line_start < buf.len() {
                 carry.extend_from_slice(&buf[line_start..]);
             }
+            if carry.len() > MAX_LINE_LEN {
+                return Err(BrcError::LineTooLong {
+                    carried: carry.len(),
+                    max_line_len: MAX_LINE_LEN,
+                    buffer_size: buf_reader.capacity(),
+                });
+            }
         }
 
         buf_reader.consume(buf_len);
     }
+    return Ok(());
 }
 
-fn find_char(buf: &[u8], target: u8) -> Option<usize> {
+pub(crate) fn find_char(buf: &[u8], target: u8) -> Option<usize> {
     if buf.len() >= 48 {
         let first = u8x16::from_slice(&buf[..16]);
         if let Some(idx) = first_match_in_u8x16(first, target) {
@@ -111,14 +155,32 @@ fn find_char(buf: &[u8], target: u8) -> Option<usize> {
         }
         None
     } else {
-        return memchr(target, buf);
+        return find_char_padded(buf, target);
     }
 }
 
+// short tails (the last line or two of a buffer) also go through SIMD, 16 bytes at a time with the
+// last load zero padded. The padding can only match a target of 0, and any real match in that
+// load comes before the padding, so the bounds check only matters then. Kept out of line so
+// find_char's hot path stays small enough to inline (inlined, it cost ~10%). Against the old memchr
+// fallback it's within noise: the tails are a tiny fraction of all searches
+#[inline(never)]
+fn find_char_padded(buf: &[u8], target: u8) -> Option<usize> {
+    let mut offset = 0;
+    while offset < buf.len() {
+        let v = load_u8x16_padded(&buf[offset..]);
+        if let Some(idx) = first_match_in_u8x16(v, target) && offset + idx < buf.len() {
+            return Some(offset + idx);
+        }
+        offset += 16;
+    }
+    return None;
+}
+
 fn load_u8x16_padded(bytes: &[u8]) -> u8x16 {
     let mut arr = [0u8 ; 16];
     let len = bytes.len().min(16);
-    arr[..len].copy_from_slice(bytes);
+    arr[..len].copy_from_slice(&bytes[..len]);
     u8x16::from_array(arr)
 }
 
@@ -167,7 +229,7 @@ fn format_output(map: &CustomHashMap) -> String {
 struct StationData {
     min_temp: i32,
     max_temp: i32,
-    total: i32,
+    total: i64,
     count: u32,
     name: Option<Vec<u8>>,
 }
@@ -185,18 +247,18 @@ impl StationData {
     pub fn add_temp(&mut self, temp: i32, name: &[u8]) {
         self.min_temp = self.min_temp.min(temp);
         self.max_temp = self.max_temp.max(temp);
-        self.total += temp;
+        self.total += temp as i64;
         self.count += 1;
         if self.name.is_none() {
             self.name = Some(name.to_vec());
         }
     }
     pub fn format_data_point(&self) -> String {
-        return format!("{}={:.1}/{:.1}/{:.1}", 
+        return format!("{}={}/{}/{}", 
             String::from_utf8(self.name.clone().unwrap()).unwrap(), 
-            0.1 * self.min_temp as f32, 
-            0.1 * self.total as f32 / self.count as f32, 
-            0.1 * self.max_temp as f32
+            format_fixed(self.min_temp as i64, 1), 
+            format_fixed(mean_half_up(self.total, self.count), 1), 
+            format_fixed(self.max_temp as i64, 1)
         );
     }
 }
@@ -214,7 +276,13 @@ impl CustomHashMap {
     pub fn get_mut(&mut self, key: &[u8]) -> &mut StationData {
         let u64_key = get_u64_key(key);
         let hashed_key = mix64(u64_key).wrapping_mul(384); // 384 is a magic seed
-        let index = hashed_key as usize % self.backing.len();
+        let mut index = hashed_key as usize % self.backing.len();
+        let mut chain = 0;
+        while let Some(name) = &self.backing[index].name && name.as_slice() != key {
+            chain += 1;
+            assert!(chain < self.backing.len(), "hash table full, more than {} distinct station names", self.backing.len());
+            index = (index + 1) % self.backing.len();
+        }
         return &mut self.backing[index];
     }
 }
@@ -0,0 +1,61 @@
+// Goal:
+//      - Get v15 style parallelism without positioned reads (read_at), for platforms where
+//      FileExt::read_at isn't available
+//
+// Change:
+//      - Keep v14's BufReader + carry scanning loop and SIMD find_char (shared with the
+//      streaming mode as stream::scan_reader)
+//      - Split the file into segments with v15's boundary search, but reading with plain seek + read
+//      - One thread per segment, each with its own File handle and BufReader seeked to the
+//      segment start and limited to the segment length via take()
+//      - Each thread opens the file itself: try_clone'd handles share the file cursor, so seeking
+//      one would move all of them
+//      - Per-thread maps are merged with v15's merge_maps
+//
+// Result:
+//      - Same output as v15
+
+
+use std::{fs::File, io::{Read, Seek, SeekFrom}, thread};
+
+use crate::{stream::scan_reader, v15::{CustomHashMap, NUM_SEGMENTS, find_line_splits, format_output, merge_maps}};
+
+pub fn run(measurements_path: &str) -> String {
+    let mut measurements_file = File::open(measurements_path).unwrap();
+
+    let split_indices = find_segment_splits(&mut measurements_file, NUM_SEGMENTS);
+
+    let handles: Vec<_> = split_indices
+        .into_iter()
+        .map(|(start, end)| {
+            let path = measurements_path.to_owned();
+            thread::spawn(move || {
+                let mut file = File::open(path).unwrap();
+                file.seek(SeekFrom::Start(start as u64)).unwrap();
+
+                let mut map = CustomHashMap::new();
+                scan_reader(file.take((end - start) as u64), &mut map).unwrap();
+                map
+            })
+        })
+        .collect();
+
+    let maps: Vec<_> = handles
+        .into_iter()
+        .map(|h|
+            h.join().unwrap()
+        )
+        .collect();
+
+    let merged_map = merge_maps(maps);
+
+    return format_output(&merged_map);
+}
+
+fn find_segment_splits(file: &mut File, num_segments: usize) -> Vec<(usize, usize)> {
+    let file_len = file.metadata().unwrap().len() as usize;
+    return find_line_splits(file_len, num_segments, |buf, pos| {
+        file.seek(SeekFrom::Start(pos as u64)).unwrap();
+        file.read(buf).unwrap()
+    });
+}
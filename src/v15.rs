@@ -15,50 +15,1030 @@
 //      - Parallelism is cool
 
 
-use std::{fs::File, i32, os::unix::fs::FileExt, simd::{Simd, cmp::SimdPartialEq, u8x16}, thread};
+use std::{collections::{BTreeMap, BinaryHeap, HashMap, HashSet}, fs::File, i32, io::{self, BufRead, BufReader, Write}, ops::ControlFlow, os::unix::fs::FileExt, path::Path, simd::{Simd, cmp::SimdPartialEq, u8x16}, sync::{Arc, Mutex, mpsc::{self, RecvTimeoutError}, atomic::{AtomicBool, AtomicUsize, Ordering}}, thread, time::{Duration, Instant}};
 
 use memchr::memchr;
 
+pub(crate) const NUM_SEGMENTS: usize = 7;
+// choose_num_segments never goes past this, however big the file or machine
+pub(crate) const MAX_SEGMENTS: usize = 64;
+pub(crate) const BYTES_PER_SEGMENT: usize = 4 * 1024 * 1024;
+// size of the chunks scan_segment_with reads a segment in
+pub(crate) const SCAN_BUF_SIZE: usize = 16 * 1024 * 1024;
+// station names are 1 to 100 bytes per the spec
+pub(crate) const MAX_NAME_LEN: usize = 100;
+
+// counters collected during a run; the probe counters are only filled in when the "stats"
+// feature is enabled, otherwise they stay at 0 and cost nothing in the hot loop
+#[derive(Debug, Clone, Default)]
+pub struct RunStats {
+    // lookups that landed on a slot holding a different station name
+    pub probe_steps: u64,
+    // longest run of such mismatches seen by a single lookup
+    pub max_probe_chain: u32,
+    // lines whose name isn't 1 to 100 bytes, lenient mode aggregates them anyway
+    pub name_length_warnings: u64,
+    // wall time of each pipeline stage, these always get filled in
+    pub split_time: Duration,
+    pub scan_time: Duration,
+    pub merge_time: Duration,
+    pub format_time: Duration,
+    // number of scanning threads the run spawned
+    pub worker_threads: usize,
+}
+
+impl RunStats {
+    pub fn total_time(&self) -> Duration {
+        self.split_time + self.scan_time + self.merge_time + self.format_time
+    }
+}
+
+#[derive(Debug)]
+pub enum BrcError {
+    Io(std::io::Error),
+    // the path exists but is a directory, fifo, device, ...
+    NotAFile(String),
+    // only produced in strict mode, carries the raw bytes of the offending line
+    MalformedLine { name: Vec<u8>, temp: Vec<u8>, reason: &'static str },
+    // the run's cancellation flag was set before it finished
+    Cancelled,
+    // detect_format saw temperatures with different numbers of fractional digits
+    MixedResolution { expected: u32, found: u32, line: Vec<u8> },
+    // open_measurements' pre-check: the path doesn't exist or can't be read
+    NotFound(String),
+    PermissionDenied(String),
+    // the bytes that can actually be read don't match the size the metadata reports, e.g. the
+    // file was truncated or is still being appended to, or it's a /proc style file reporting 0
+    SizeMismatch { path: String, reported: u64 },
+    // run_with_splits got splits that don't tile the file from 0 to its current length, most
+    // likely the file changed since precompute_splits ran
+    StaleSplits { path: String, file_len: u64 },
+    // v14's carry guard: a line was carried across reads for longer than any valid line can be
+    LineTooLong { carried: usize, max_line_len: usize, buffer_size: usize },
+}
+
+impl std::fmt::Display for BrcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BrcError::Io(e) => write!(f, "io error: {}", e),
+            BrcError::NotAFile(path) => write!(f, "\"{}\" is not a regular file", path),
+            BrcError::MalformedLine { name, temp, reason } => write!(f, "malformed line for station \"{}\" ({} bytes) with temperature \"{}\": {}",
+                String::from_utf8_lossy(name),
+                name.len(),
+                String::from_utf8_lossy(temp),
+                reason
+            ),
+            BrcError::Cancelled => write!(f, "run was cancelled"),
+            BrcError::MixedResolution { expected, found, line } => write!(f, "file mixes temperature resolutions: expected {} fractional digits but line \"{}\" has {}",
+                expected,
+                String::from_utf8_lossy(line),
+                found
+            ),
+            BrcError::NotFound(path) => write!(f, "\"{}\" does not exist", path),
+            BrcError::PermissionDenied(path) => write!(f, "no permission to read \"{}\"", path),
+            BrcError::SizeMismatch { path, reported } => write!(f, "\"{}\" reports a size of {} bytes but its readable contents differ", path, reported),
+            BrcError::StaleSplits { path, file_len } => write!(f, "precomputed splits don't cover \"{}\" ({} bytes), was it changed since they were computed?", path, file_len),
+            BrcError::LineTooLong { carried, max_line_len, buffer_size } => write!(f, "carried {} bytes across reads without finding the end of the line, but no valid line is longer than {} bytes (buffer size {})",
+                carried,
+                max_line_len,
+                buffer_size
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BrcError {}
+
+impl From<std::io::Error> for BrcError {
+    fn from(e: std::io::Error) -> Self {
+        BrcError::Io(e)
+    }
+}
+
 pub fn run(measurements_path: &str) -> String {
-    const NUM_SEGMENTS: usize = 7;
-    let measurements_file = std::fs::File::open(measurements_path).unwrap();
+    return try_run(measurements_path).unwrap_or_else(|e| panic!("{}", e));
+}
 
-    let split_indices = find_segment_splits(&measurements_file, NUM_SEGMENTS);
+pub fn try_run(measurements_path: &str) -> Result<String, BrcError> {
+    let (result, _stats) = run_with_stats(measurements_path)?;
+    return Ok(result);
+}
+
+#[derive(Debug, Clone)]
+pub struct RunOptions {
+    // reject malformed lines with BrcError::MalformedLine instead of aggregating whatever
+    // parse_temp makes of them
+    pub strict: bool,
+    // checked by every worker between chunks; once set the run stops early and returns
+    // BrcError::Cancelled
+    pub cancel: Option<Arc<AtomicBool>>,
+    pub field_order: FieldOrder,
+    // the byte between the integer and fractional digits, b'.' or b',' for "12,3" style files.
+    // Temps are split from names on ';', so a comma here can't be confused with a field delimiter.
+    // parse_temp skips every non-digit so the lenient path reads either separator the same way,
+    // strict mode checks that the configured one is used
+    pub decimal_sep: u8,
+    // unit the file's temperatures are written in, converted to Celsius as each line is parsed.
+    // Strict mode still checks the raw value against the spec's -?d?d.d format, so Kelvin (and
+    // Fahrenheit above 99.9) files have to be read leniently
+    pub input_unit: Unit,
+    // run-length fast path for files with runs of identical consecutive lines: a line that's
+    // byte-for-byte the previous one reuses its slot and parsed temp instead of being hashed and
+    // parsed again. Only pays off on run-heavy data: on a 630 MB file of runs averaging 100 lines
+    // it took 1.3s down to 1.0s, but on normal data the extra compare and copy of every line made
+    // a 630 MB file ~30% slower (1.5s to 1.9s), so it's off by default. Lenient mode only
+    pub collapse_runs: bool,
+}
+
+impl Default for RunOptions {
+    fn default() -> Self {
+        Self {
+            strict: false,
+            cancel: None,
+            field_order: FieldOrder::default(),
+            decimal_sep: b'.',
+            input_unit: Unit::default(),
+            collapse_runs: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Unit {
+    #[default]
+    Celsius,
+    Kelvin,
+    Fahrenheit,
+}
 
+impl Unit {
+    // tenths of this unit to tenths of a degree Celsius. The exact result usually isn't a whole
+    // tenth (300.1K is 26.95C, every Kelvin reading is off by exactly half a tenth), so it's
+    // rounded half to even: always rounding those halves the same way would shift every Kelvin
+    // reading, and with it the mean, by 0.05 in one direction
+    #[inline(always)]
+    pub fn to_celsius_tenths(self, temp: i32) -> i32 {
+        return match self {
+            Unit::Celsius => temp,
+            // C = K - 273.15, done in hundredths
+            Unit::Kelvin => div_round_half_even(temp as i64 * 10 - 27_315, 10),
+            // C = (F - 32) * 5 / 9
+            Unit::Fahrenheit => div_round_half_even((temp as i64 - 320) * 5, 9),
+        };
+    }
+}
+
+#[inline(always)]
+fn div_round_half_even(numerator: i64, denominator: i64) -> i32 {
+    let quotient = numerator.div_euclid(denominator);
+    let twice_remainder = 2 * numerator.rem_euclid(denominator);
+    let round_up = twice_remainder > denominator || (twice_remainder == denominator && quotient % 2 != 0);
+    return (quotient + round_up as i64) as i32;
+}
+
+// which side of the first ; holds the station name. Some datasets write lines as temp;name
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FieldOrder {
+    #[default]
+    NameFirst,
+    TempFirst,
+}
+
+impl FieldOrder {
+    // takes the slices before and after the ; and returns them as (name, temp)
+    #[inline(always)]
+    pub fn assign<'a>(self, first: &'a [u8], second: &'a [u8]) -> (&'a [u8], &'a [u8]) {
+        return match self {
+            FieldOrder::NameFirst => (first, second),
+            FieldOrder::TempFirst => (second, first),
+        };
+    }
+}
+
+// every knob of a run in one place, built with chainable setters on top of the defaults:
+//      RunConfig::new().threads(4).strict(true).format(Format::Json)
+#[derive(Debug, Clone)]
+pub struct RunConfig {
+    options: RunOptions,
+    // None uses available_parallelism
+    threads: Option<usize>,
+    format: Format,
+    // only applies to Format::Braced, Json is always sorted by name
+    sort_key: SortKey,
+    // false skips sorting entirely (see format_output_unsorted), sort_key is then ignored
+    sorted: bool,
+    // applied to each name when it's printed, aggregation still goes by the exact input name.
+    // Braced only, and when set it takes over ordering from sort_key/sorted: entries are sorted
+    // by the transformed names if sort_by_display_name, by the original ones otherwise
+    display_name: Option<fn(&[u8]) -> Vec<u8>>,
+    sort_by_display_name: bool,
+}
+
+impl Default for RunConfig {
+    fn default() -> Self {
+        Self {
+            options: RunOptions::default(),
+            threads: None,
+            format: Format::Braced,
+            sort_key: SortKey::default(),
+            sorted: true,
+            display_name: None,
+            sort_by_display_name: true,
+        }
+    }
+}
+
+impl RunConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = Some(threads);
+        self
+    }
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.options.strict = strict;
+        self
+    }
+    pub fn cancel(mut self, cancel: Arc<AtomicBool>) -> Self {
+        self.options.cancel = Some(cancel);
+        self
+    }
+    pub fn field_order(mut self, field_order: FieldOrder) -> Self {
+        self.options.field_order = field_order;
+        self
+    }
+    pub fn decimal_sep(mut self, decimal_sep: u8) -> Self {
+        self.options.decimal_sep = decimal_sep;
+        self
+    }
+    pub fn input_unit(mut self, input_unit: Unit) -> Self {
+        self.options.input_unit = input_unit;
+        self
+    }
+    pub fn collapse_runs(mut self, collapse_runs: bool) -> Self {
+        self.options.collapse_runs = collapse_runs;
+        self
+    }
+    pub fn format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+    pub fn sort_by(mut self, sort_key: SortKey) -> Self {
+        self.sort_key = sort_key;
+        self
+    }
+    pub fn sorted(mut self, sorted: bool) -> Self {
+        self.sorted = sorted;
+        self
+    }
+    pub fn display_name(mut self, transform: fn(&[u8]) -> Vec<u8>, sort_by_display_name: bool) -> Self {
+        self.display_name = Some(transform);
+        self.sort_by_display_name = sort_by_display_name;
+        self
+    }
+    fn thread_budget(&self) -> usize {
+        return self.threads.unwrap_or_else(|| thread::available_parallelism().map_or(NUM_SEGMENTS, |n| n.get()));
+    }
+}
+
+pub fn run_config(measurements_path: &str, config: &RunConfig) -> Result<String, BrcError> {
+    let (result, _stats) = run_config_with_stats(measurements_path, config)?;
+    return Ok(result);
+}
+
+pub fn run_config_with_stats(measurements_path: &str, config: &RunConfig) -> Result<(String, RunStats), BrcError> {
+    let (map, mut stats) = aggregate_files(&[measurements_path], config.thread_budget(), &config.options)?;
+
+    let start = Instant::now();
+    let result = format_with_config(&map, config);
+    stats.format_time = start.elapsed();
+
+    return Ok((result, stats));
+}
+
+fn format_with_config(map: &CustomHashMap, config: &RunConfig) -> String {
+    return match config.format {
+        Format::Braced if let Some(transform) = config.display_name => format_output_display(map, transform, config.sort_by_display_name),
+        Format::Braced if !config.sorted => format_output_unsorted(map),
+        Format::Braced => format_output_sorted_by(map, config.sort_key),
+        Format::Json => format_output_json(map),
+    };
+}
+
+// line aligned [start, end) byte ranges of the file, for run_with_splits. Finding them reads a few
+// bytes at every boundary, which repeated runs over the same file can skip by computing them once
+pub fn precompute_splits(measurements_path: &str, num_segments: usize) -> Vec<(u64, u64)> {
+    let measurements_file = open_measurements(measurements_path).unwrap_or_else(|e| panic!("{}", e));
+    return find_segment_splits(&measurements_file, num_segments.max(1))
+        .into_iter()
+        .map(|(start, end)| (start as u64, end as u64))
+        .collect();
+}
+
+// run_config with splits from precompute_splits instead of searching for boundaries. The splits
+// are only checked to still tile the whole file, a file that changed but kept its length isn't
+// caught
+pub fn run_with_splits(measurements_path: &str, splits: &[(u64, u64)], config: &RunConfig) -> Result<String, BrcError> {
+    let measurements_file = open_measurements(measurements_path)?;
+    let file_len = measurements_file.metadata()?.len();
+    let tiles_file = splits.first().is_some_and(|split| split.0 == 0)
+        && splits.windows(2).all(|pair| pair[0].1 == pair[1].0)
+        && splits.iter().all(|(start, end)| start <= end)
+        && splits.last().is_some_and(|split| split.1 == file_len);
+    if !tiles_file {
+        return Err(BrcError::StaleSplits { path: measurements_path.to_owned(), file_len });
+    }
+
+    let jobs: Vec<_> = splits.iter().map(|&(start, end)| (&measurements_file, start as usize, end as usize)).collect();
+    let (mut map, _stats) = aggregate_jobs(&jobs, config.thread_budget(), &config.options)?;
+    map.ascii_hint = detect_ascii(&measurements_file);
+    return Ok(format_with_config(&map, config));
+}
+
+// what detect_format learned about the temperatures from the start of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormatProfile {
+    pub fractional_digits: u32,
+    pub has_negatives: bool,
+}
+
+// looks at the first sample_lines lines to work out how many fractional digits the temperatures
+// have and whether any are negative. A file mixing resolutions is an error rather than a guess
+pub fn detect_format(measurements_path: &str, sample_lines: usize) -> Result<FormatProfile, BrcError> {
+    let file = open_measurements(measurements_path)?;
+    let mut fractional_digits = None;
+    let mut has_negatives = false;
+
+    for line in BufReader::new(file).split(b'\n').take(sample_lines) {
+        let line = line?;
+        let Some(semicolon_pos) = memchr(b';', &line) else {
+            continue;
+        };
+        let temp_slice = trim_cr(&line[semicolon_pos+1..]);
+        has_negatives |= temp_slice.first() == Some(&b'-');
+        let digits = memchr(b'.', temp_slice).map_or(0, |dot_pos| (temp_slice.len() - dot_pos - 1) as u32);
+
+        match fractional_digits {
+            None => fractional_digits = Some(digits),
+            Some(expected) if expected != digits => {
+                return Err(BrcError::MixedResolution { expected, found: digits, line });
+            }
+            Some(_) => {}
+        }
+    }
+
+    return Ok(FormatProfile { fractional_digits: fractional_digits.unwrap_or(1), has_negatives });
+}
+
+// like run, but detects the temperature resolution first and formats with that many decimals.
+// parse_temp already skips the '.', so it reads any resolution as an integer in those units
+pub fn run_auto(measurements_path: &str) -> Result<String, BrcError> {
+    let profile = detect_format(measurements_path, 10_000)?;
+    let (mut map, _stats) = aggregate_file(measurements_path, &RunOptions::default())?;
+    map.fractional_digits = profile.fractional_digits;
+    return Ok(format_output(&map));
+}
+
+pub fn run_with_options(measurements_path: &str, options: &RunOptions) -> Result<String, BrcError> {
+    let (result, _stats) = run_inner(measurements_path, options)?;
+    return Ok(result);
+}
+
+// opens the measurements file and checks it can actually be processed before any worker is
+// spawned. Otherwise the failure only surfaces as a read_at panic inside a worker, which the
+// caller sees as an opaque thread join panic:
+//      - it has to be a regular file: File::open happily opens a directory on some platforms
+//      - missing and unreadable files get their own errors instead of a bare Io
+//      - the last byte has to be readable and nothing may follow it, so the size every split is
+//        computed from matches what the workers will read
+pub(crate) fn open_measurements(measurements_path: &str) -> Result<File, BrcError> {
+    let measurements_file = File::open(measurements_path).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => BrcError::NotFound(measurements_path.to_owned()),
+        io::ErrorKind::PermissionDenied => BrcError::PermissionDenied(measurements_path.to_owned()),
+        _ => BrcError::Io(e),
+    })?;
+    let metadata = measurements_file.metadata()?;
+    if !metadata.is_file() {
+        return Err(BrcError::NotAFile(measurements_path.to_owned()));
+    }
+
+    let file_len = metadata.len();
+    let mut probe = [0u8; 1];
+    let last_byte_readable = file_len == 0 || measurements_file.read_at(&mut probe, file_len - 1)? == 1;
+    let ends_at_file_len = measurements_file.read_at(&mut probe, file_len)? == 0;
+    if !last_byte_readable || !ends_at_file_len {
+        return Err(BrcError::SizeMismatch { path: measurements_path.to_owned(), reported: file_len });
+    }
+    return Ok(measurements_file);
+}
+
+pub fn run_with_stats(measurements_path: &str) -> Result<(String, RunStats), BrcError> {
+    return run_inner(measurements_path, &RunOptions::default());
+}
+
+fn run_inner(measurements_path: &str, options: &RunOptions) -> Result<(String, RunStats), BrcError> {
+    let (merged_map, mut stats) = aggregate_file(measurements_path, options)?;
+
+    let start = Instant::now();
+    let result = format_output(&merged_map);
+    stats.format_time = start.elapsed();
+
+    #[cfg(feature = "profiling")]
+    eprintln!("[profiling] {}: split {:?}, scan {:?}, merge {:?}, format {:?}, total {:?} ({} workers)",
+        measurements_path,
+        stats.split_time,
+        stats.scan_time,
+        stats.merge_time,
+        stats.format_time,
+        stats.total_time(),
+        stats.worker_threads
+    );
+
+    return Ok((result, stats));
+}
+
+// runs the split/scan/merge stages for one file and returns the merged map, leaving formatting
+// to the caller (format_time is left at 0)
+pub(crate) fn aggregate_file(measurements_path: &str, options: &RunOptions) -> Result<(CustomHashMap, RunStats), BrcError> {
+    let start = Instant::now();
+    let measurements_file = open_measurements(measurements_path)?;
+
+    let num_segments = choose_num_segments(measurements_file.metadata()?.len() as usize);
+    let split_indices = find_segment_splits(&measurements_file, num_segments);
+    let ascii_hint = detect_ascii(&measurements_file);
+    let modulus = Modulus::from_env();
+    let key_hash = KeyHash::from_env();
+    let split_time = start.elapsed();
+
+    let start = Instant::now();
     let handles: Vec<_> = split_indices
         .into_iter()
         .map(|(start, end)| {
+            // the clone only keeps thread::spawn's 'static bound happy. All reads are read_at
+            // (pread), which never touches the fd's shared offset, so workers could just as well
+            // share one handle; see misc::test_shared_file_reads
             let file = measurements_file.try_clone().unwrap();
+            let options = options.clone();
             thread::spawn(move || {
-                scan_file_segment(&file, start, end)
+                scan_file_segment(&file, start, end, modulus, key_hash, &options)
             })
         })
         .collect();
     
-    let maps: Vec<_> = handles
+    let maps = handles
         .into_iter()
         .map(|h| 
             h.join().unwrap()
         )
+        .collect::<Result<Vec<_>, _>>()?;
+    let scan_time = start.elapsed();
+
+    let start = Instant::now();
+    let mut stats = collect_stats(&maps);
+    let mut merged_map = merge_maps(maps);
+    merged_map.ascii_hint = ascii_hint;
+    let merge_time = start.elapsed();
+
+    stats.split_time = split_time;
+    stats.scan_time = scan_time;
+    stats.merge_time = merge_time;
+    stats.worker_threads = num_segments;
+
+    return Ok((merged_map, stats));
+}
+
+// like run, but each worker sends its map to a merger thread as soon as it finishes, so merging
+// the early finishers overlaps with the slowest worker's scan instead of waiting for all of them
+pub fn run_streamed_merge(measurements_path: &str) -> String {
+    let map = aggregate_file_streamed(measurements_path, &RunOptions::default())
+        .unwrap_or_else(|e| panic!("{}", e));
+    return format_output(&map);
+}
+
+pub(crate) fn aggregate_file_streamed(measurements_path: &str, options: &RunOptions) -> Result<CustomHashMap, BrcError> {
+    let measurements_file = open_measurements(measurements_path)?;
+    let num_segments = choose_num_segments(measurements_file.metadata()?.len() as usize);
+    let split_indices = find_segment_splits(&measurements_file, num_segments);
+    let ascii_hint = detect_ascii(&measurements_file);
+    let modulus = Modulus::from_env();
+    let key_hash = KeyHash::from_env();
+
+    let (tx, rx) = mpsc::channel::<Result<CustomHashMap, BrcError>>();
+    let merger = thread::spawn(move || {
+        let mut merged_map = CustomHashMap::with_strategy(modulus, key_hash);
+        // keep draining after an error so no worker blocks, but only report the first one
+        let mut error = None;
+        for result in rx {
+            match result {
+                Ok(map) if error.is_none() => merge_into(&mut merged_map, map),
+                Ok(_) => {}
+                Err(e) => { error.get_or_insert(e); }
+            }
+        }
+        return match error {
+            Some(e) => Err(e),
+            None => Ok(merged_map),
+        };
+    });
+
+    for (start, end) in split_indices {
+        let file = measurements_file.try_clone()?;
+        let options = options.clone();
+        let tx = tx.clone();
+        thread::spawn(move || {
+            tx.send(scan_file_segment(&file, start, end, modulus, key_hash, &options)).unwrap();
+        });
+    }
+    // the merger stops once every worker's sender is gone
+    drop(tx);
+
+    let mut merged_map = merger.join().unwrap()?;
+    merged_map.ascii_hint = ascii_hint;
+    return Ok(merged_map);
+}
+
+// processes several files with a shared pool of available_parallelism threads and merges them
+// into a single result
+pub fn run_many(measurements_paths: &[&str]) -> String {
+    let thread_budget = thread::available_parallelism().map_or(NUM_SEGMENTS, |n| n.get());
+    return run_many_with_threads(measurements_paths, thread_budget);
+}
+
+pub fn run_many_with_threads(measurements_paths: &[&str], thread_budget: usize) -> String {
+    let (map, _stats) = aggregate_files(measurements_paths, thread_budget, &RunOptions::default())
+        .unwrap_or_else(|e| panic!("{}", e));
+    return format_output(&map);
+}
+
+// interim result of a run_with_snapshots run. output only covers the jobs_done jobs that have
+// been fully merged, formatted like the final output
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub jobs_done: usize,
+    pub jobs_total: usize,
+    pub output: String,
+}
+
+// bytes per job in run_with_snapshots. Snapshots only see finished jobs, so this (not the
+// interval) bounds how stale a snapshot can be
+const SNAPSHOT_JOB_BYTES: usize = 64 * 1024 * 1024;
+
+// for monitoring long runs: like run, but every interval on_snapshot is called (from a
+// background thread) with the aggregates of everything merged so far
+pub fn run_with_snapshots<F: FnMut(Snapshot) + Send>(measurements_path: &str, interval: Duration, on_snapshot: F) -> Result<String, BrcError> {
+    let measurements_file = open_measurements(measurements_path)?;
+    let num_jobs = (measurements_file.metadata()?.len() as usize).div_ceil(SNAPSHOT_JOB_BYTES).max(1);
+    let split_indices = find_segment_splits(&measurements_file, num_jobs);
+    let threads = thread::available_parallelism().map_or(NUM_SEGMENTS, |n| n.get());
+    let modulus = Modulus::from_env();
+    let key_hash = KeyHash::from_env();
+    let options = RunOptions::default();
+
+    let scan_job = |job: usize| {
+        let (start, end) = split_indices[job];
+        scan_file_segment(&measurements_file, start, end, modulus, key_hash, &options)
+    };
+    let mut map = aggregate_with_snapshots(split_indices.len(), threads, CustomHashMap::with_strategy(modulus, key_hash), scan_job, interval, on_snapshot)?;
+    map.ascii_hint = detect_ascii(&measurements_file);
+    return Ok(format_output(&map));
+}
+
+// workers pull job indices off a shared counter and fold each finished job's map into one shared
+// map. The map and the finished job count sit behind the same lock and a job is merged in one go
+// while holding it, so a snapshot sees each job either completely or not at all, never half
+// merged. scan_job's maps have to use the same Modulus/KeyHash as merged
+pub(crate) fn aggregate_with_snapshots<J, F>(num_jobs: usize, threads: usize, merged: CustomHashMap, scan_job: J, interval: Duration, mut on_snapshot: F) -> Result<CustomHashMap, BrcError>
+where
+    J: Fn(usize) -> Result<CustomHashMap, BrcError> + Sync,
+    F: FnMut(Snapshot) + Send,
+{
+    let state = Mutex::new((merged, 0usize));
+    let error = Mutex::new(None);
+    let next_job = AtomicUsize::new(0);
+    let (done_tx, done_rx) = mpsc::channel::<()>();
+
+    thread::scope(|s| {
+        let state = &state;
+        s.spawn(move || {
+            // wakes up every interval until the workers are done and done_tx is dropped
+            while let Err(RecvTimeoutError::Timeout) = done_rx.recv_timeout(interval) {
+                let snapshot = {
+                    let guard = state.lock().unwrap();
+                    Snapshot { jobs_done: guard.1, jobs_total: num_jobs, output: format_output(&guard.0) }
+                };
+                on_snapshot(snapshot);
+            }
+        });
+
+        let workers: Vec<_> = (0..threads.clamp(1, num_jobs.max(1)))
+            .map(|_| {
+                s.spawn(|| {
+                    loop {
+                        let job = next_job.fetch_add(1, Ordering::Relaxed);
+                        if job >= num_jobs || error.lock().unwrap().is_some() {
+                            break;
+                        }
+                        match scan_job(job) {
+                            Ok(map) => {
+                                let mut guard = state.lock().unwrap();
+                                merge_into(&mut guard.0, map);
+                                guard.1 += 1;
+                            }
+                            Err(e) => { error.lock().unwrap().get_or_insert(e); }
+                        }
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        drop(done_tx);
+    });
+
+    if let Some(e) = error.into_inner().unwrap() {
+        return Err(e);
+    }
+    let (merged, _jobs_done) = state.into_inner().unwrap();
+    return Ok(merged);
+}
+
+// like run, but only stations in allowed are aggregated, every other line is dropped before it
+// touches the map. Each worker's map starts out with the allowed names already stored (with no
+// readings), and nothing else is ever inserted, so the check is the map's own probe: a name that
+// isn't allowed ends on an empty slot
+pub fn run_allowlist(measurements_path: &str, allowed: &HashSet<Vec<u8>>) -> String {
+    let measurements_file = open_measurements(measurements_path).unwrap_or_else(|e| panic!("{}", e));
+    let split_indices = find_segment_splits(&measurements_file, choose_num_segments(measurements_file.metadata().unwrap().len() as usize));
+    let modulus = Modulus::from_env();
+    let key_hash = KeyHash::from_env();
+
+    let maps = thread::scope(|s| {
+        let handles: Vec<_> = split_indices
+            .iter()
+            .map(|&(start, end)| {
+                let file = &measurements_file;
+                s.spawn(move || {
+                    let mut map = CustomHashMap::with_strategy(modulus, key_hash);
+                    for name in allowed {
+                        map.get_mut(name).name = Some(name.clone());
+                    }
+                    let _ = scan_segment_with(file, start, end, None, |name_slice, temp_slice| {
+                        let (index, _) = map.probe(name_slice);
+                        if map.backing[index].name.is_some() {
+                            map.backing[index].add_temp(parse_temp(temp_slice), name_slice);
+                        }
+                        ControlFlow::Continue(())
+                    });
+                    map
+                })
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect::<Vec<_>>()
+    });
+
+    let mut merged_map = merge_maps(maps);
+    merged_map.ascii_hint = detect_ascii(&measurements_file);
+    return format_output(&merged_map);
+}
+
+// for input sorted by station name. Every station's lines are then consecutive, so a worker only
+// compares each name against the previous one and hashes once per station per segment instead of
+// once per line. Groups are merged into the map by name, so unsorted input still gives the right
+// result, just without the speedup; debug builds check the order and panic on unsorted input to
+// catch callers that don't hold up their end
+pub fn run_sorted_input(measurements_path: &str) -> String {
+    let measurements_file = open_measurements(measurements_path).unwrap_or_else(|e| panic!("{}", e));
+    let split_indices = find_segment_splits(&measurements_file, choose_num_segments(measurements_file.metadata().unwrap().len() as usize));
+
+    let scanned = thread::scope(|s| {
+        let handles: Vec<_> = split_indices
+            .iter()
+            .map(|&(start, end)| {
+                let file = &measurements_file;
+                s.spawn(move || scan_sorted_segment(file, start, end))
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect::<Vec<_>>()
+    });
+
+    // each worker checked its own segment, what's left is the order across segment boundaries
+    if cfg!(debug_assertions) {
+        let edges: Vec<_> = scanned.iter().filter_map(|(_, edges)| edges.as_ref()).collect();
+        for pair in edges.windows(2) {
+            let (previous_last, next_first) = (&pair[0].1, &pair[1].0);
+            assert!(previous_last <= next_first, "input is not sorted by station name: \"{}\" comes after \"{}\"",
+                String::from_utf8_lossy(next_first),
+                String::from_utf8_lossy(previous_last)
+            );
+        }
+    }
+
+    let mut merged_map = merge_maps(scanned.into_iter().map(|(map, _)| map).collect());
+    merged_map.ascii_hint = detect_ascii(&measurements_file);
+    return format_output(&merged_map);
+}
+
+// first and last station name of a segment
+type NameRange = (Vec<u8>, Vec<u8>);
+
+// returns the segment's map and its name range, if it has any lines
+fn scan_sorted_segment(file: &File, start_pos: usize, end_pos: usize) -> (CustomHashMap, Option<NameRange>) {
+    let mut map = CustomHashMap::with_strategy(Modulus::from_env(), KeyHash::from_env());
+    let mut first_name = None;
+    // the station of the current run of lines, aggregated on the side until the name changes
+    let mut group = StationData::new();
+
+    let _ = scan_segment_with(file, start_pos, end_pos, None, |name_slice, temp_slice| {
+        let group_name = group.name.as_deref();
+        if group_name != Some(name_slice) {
+            if let Some(group_name) = group_name {
+                debug_assert!(group_name < name_slice, "input is not sorted by station name: \"{}\" comes after \"{}\"",
+                    String::from_utf8_lossy(name_slice),
+                    String::from_utf8_lossy(group_name)
+                );
+                let finished = std::mem::replace(&mut group, StationData::new());
+                map.get_mut(finished.name.as_deref().unwrap()).merge_from(finished);
+            } else {
+                first_name = Some(name_slice.to_vec());
+            }
+            group.name = Some(name_slice.to_vec());
+        }
+        group.add_repeated_temp(parse_temp(temp_slice));
+        ControlFlow::Continue(())
+    });
+
+    let Some(first_name) = first_name else {
+        return (map, None);
+    };
+    let last_name = group.name.clone().unwrap();
+    map.get_mut(&last_name).merge_from(group);
+    return (map, Some((first_name, last_name)));
+}
+
+// scans path with up to threads workers and merges the result into a caller owned map, so results
+// from several sources can be accumulated without keeping the intermediate maps around. Merging
+// goes through map.get_mut by name, so map doesn't need to use the same Modulus/KeyHash as the
+// scan
+pub fn accumulate_into(measurements_path: &str, map: &mut CustomHashMap, threads: usize) {
+    let (scanned, _stats) = aggregate_files(&[measurements_path], threads, &RunOptions::default())
+        .unwrap_or_else(|e| panic!("{}", e));
+    merge_by_name(map, scanned);
+}
+
+// running aggregate_file on several files at once would spawn a thread per segment of every file and
+// oversubscribe the cores. Instead every file's segments go into one job list, and a fixed pool
+// of thread_budget workers pulls jobs off it, each worker accumulating into a single map
+pub(crate) fn aggregate_files(measurements_paths: &[&str], thread_budget: usize, options: &RunOptions) -> Result<(CustomHashMap, RunStats), BrcError> {
+    let start = Instant::now();
+    let files = measurements_paths
+        .iter()
+        .map(|path| open_measurements(path))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let jobs: Vec<_> = files
+        .iter()
+        .flat_map(|file| find_segment_splits(file, choose_num_segments(file.metadata().unwrap().len() as usize)).into_iter().map(move |(start, end)| (file, start, end)))
         .collect();
-    
-    let mut merged_map = CustomHashMap::new();
-    for i in 0..merged_map.backing.len() {
-        if maps[0].backing[i].count == 0 {
+    let ascii_hint = files.iter().all(detect_ascii);
+    let split_time = start.elapsed();
+
+    let (mut merged_map, mut stats) = aggregate_jobs(&jobs, thread_budget, options)?;
+    merged_map.ascii_hint = ascii_hint;
+    stats.split_time = split_time;
+
+    return Ok((merged_map, stats));
+}
+
+// the scan and merge half of aggregate_files: a pool of thread_budget workers pulls (file, start,
+// end) jobs off the list, each worker accumulating into a single map
+fn aggregate_jobs(jobs: &[(&File, usize, usize)], thread_budget: usize, options: &RunOptions) -> Result<(CustomHashMap, RunStats), BrcError> {
+    let modulus = Modulus::from_env();
+    let key_hash = KeyHash::from_env();
+    let num_workers = thread_budget.min(jobs.len()).max(1);
+
+    let start = Instant::now();
+    let next_job = AtomicUsize::new(0);
+    let maps = thread::scope(|s| {
+        let handles: Vec<_> = (0..num_workers)
+            .map(|_| {
+                s.spawn(|| {
+                    let mut map = CustomHashMap::with_strategy(modulus, key_hash);
+                    while let Some(&(file, start, end)) = jobs.get(next_job.fetch_add(1, Ordering::Relaxed)) {
+                        scan_file_segment_into(&mut map, file, start, end, options)?;
+                    }
+                    return Ok(map);
+                })
+            })
+            .collect();
+
+        return handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .collect::<Result<Vec<_>, BrcError>>();
+    })?;
+    let scan_time = start.elapsed();
+
+    let start = Instant::now();
+    let mut stats = collect_stats(&maps);
+    let merged_map = merge_maps(maps);
+    let merge_time = start.elapsed();
+
+    stats.scan_time = scan_time;
+    stats.merge_time = merge_time;
+    stats.worker_threads = num_workers;
+
+    return Ok((merged_map, stats));
+}
+
+// reads a newline separated list of measurement files (blank lines and # comments are skipped)
+// and processes them all with run_many
+pub fn run_manifest(manifest_path: &str) -> String {
+    let manifest = std::fs::read_to_string(manifest_path).unwrap();
+    let paths: Vec<_> = manifest
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect();
+
+    return run_many(&paths);
+}
+
+// for rotated logs: processes base_path plus base_path.1 ..= base_path.max_index and merges them
+// all. Rotation can leave gaps (an index that was deleted or not written yet), so missing numbered
+// files are skipped; base_path itself has to exist
+pub fn run_rotated(base_path: &str, max_index: usize) -> String {
+    let rotated: Vec<_> = (1..=max_index)
+        .map(|index| format!("{}.{}", base_path, index))
+        .filter(|path| Path::new(path).exists())
+        .collect();
+    let paths: Vec<_> = std::iter::once(base_path).chain(rotated.iter().map(|path| path.as_str())).collect();
+
+    return run_many(&paths);
+}
+
+fn collect_stats(maps: &[CustomHashMap]) -> RunStats {
+    let mut stats = RunStats::default();
+    for map in maps {
+        stats.name_length_warnings += map.name_length_warnings;
+        #[cfg(feature = "stats")]
+        {
+            stats.probe_steps += map.probe_steps;
+            stats.max_probe_chain = stats.max_probe_chain.max(map.max_probe_chain);
+        }
+    }
+    return stats;
+}
+
+// folds the per-segment maps into one, moving station names out of the source maps instead of
+// cloning them since the sources are discarded afterwards. Only the slots each map recorded as
+// occupied are visited, so the cost is stations * maps rather than table size * maps
+pub(crate) fn merge_maps(maps: Vec<CustomHashMap>) -> CustomHashMap {
+    let (modulus, key_hash) = maps.first().map_or((Modulus::default(), KeyHash::default()), |map| (map.modulus, map.key_hash));
+    let mut merged_map = CustomHashMap::with_strategy(modulus, key_hash);
+    for map in maps {
+        merge_into(&mut merged_map, map);
+    }
+    return merged_map;
+}
+
+// folds one map into merged by name. Where a station ends up depends on the table's Modulus and
+// KeyHash and, with probing, on which colliding names got there first, so slots can't be matched
+// up between maps even when both use the same strategy
+pub(crate) fn merge_into(merged_map: &mut CustomHashMap, mut map: CustomHashMap) {
+    for index in std::mem::take(&mut map.occupied) {
+        // taking the slot out means an index listed twice is merged only once
+        let data = std::mem::replace(&mut map.backing[index as usize], StationData::new());
+        if data.count == 0 {
             continue;
         }
-        let accum = &mut merged_map.backing[i];
-        for j in 0..NUM_SEGMENTS {
-            let other = &maps[j].backing[i];
-            accum.merge_with(other);
+        merged_map.get_mut(data.name.as_deref().unwrap()).merge_from(data);
+    }
+}
+
+// folds map into merged through merged.get_mut, so the two tables can have any size and hash.
+// Same result as merge_into, but walks map's whole table instead of its occupied slots
+pub(crate) fn merge_by_name(merged_map: &mut CustomHashMap, map: CustomHashMap) {
+    for data in map.into_stations() {
+        merged_map.get_mut(data.name.as_deref().unwrap()).merge_from(data);
+    }
+}
+
+// one segment per started 4 MiB, capped at twice the core count (more segments than cores lets
+// one segment's reads overlap with another's parsing) and at MAX_SEGMENTS. Files under 4 MiB get
+// a single segment, at that size spawning threads costs more than it saves
+pub(crate) fn choose_num_segments(file_len: usize) -> usize {
+    let cores = thread::available_parallelism().map_or(NUM_SEGMENTS, |n| n.get());
+    let cap = (2 * cores).min(MAX_SEGMENTS);
+    return file_len.div_ceil(BYTES_PER_SEGMENT).clamp(1, cap);
+}
+
+// cheap check of the first megabyte for bytes >= 0x80. This is only an optimization hint for
+// formatting, a file that turns multibyte after the first megabyte still formats correctly
+pub(crate) fn detect_ascii(file: &File) -> bool {
+    const SAMPLE_SIZE: usize = 1024 * 1024;
+    let mut buf = vec![0u8; SAMPLE_SIZE];
+    let mut len = 0;
+    while len < SAMPLE_SIZE {
+        match file.read_at(&mut buf[len..], len as u64) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => len += n,
         }
     }
 
-    return format_output(&merged_map);
+    let high_bit = Simd::splat(0x80);
+    let mut chunks = buf[..len].chunks_exact(16);
+    for chunk in &mut chunks {
+        if (u8x16::from_slice(chunk) & high_bit).simd_ne(Simd::splat(0)).any() {
+            return false;
+        }
+    }
+    return chunks.remainder().is_ascii();
+}
+
+// advances offset to the start of the next UTF-8 character (or buf.len()), skipping over
+// continuation bytes. Splits made by find_segment_splits never need this: they land right after a
+// \n, and \n can't appear inside a multibyte character, so a line boundary is always a character
+// boundary too. This is for modes that cut buffers at arbitrary offsets
+pub(crate) fn snap_to_char_boundary(buf: &[u8], mut offset: usize) -> usize {
+    while offset < buf.len() && (buf[offset] & 0b1100_0000) == 0b1000_0000 {
+        offset += 1;
+    }
+    return offset;
+}
+
+// true if both maps hold the same stations with the same aggregates, whichever slots they're in.
+// Where a station ends up depends on the strategy and (with probing) on insertion order, so this
+// is more robust than comparing slot by slot, and doesn't depend on formatting like comparing
+// outputs does
+pub(crate) fn maps_equal(a: &CustomHashMap, b: &CustomHashMap) -> bool {
+    let aggregates = |map: &CustomHashMap| map
+        .stations()
+        .map(|data| (data.name.as_deref().unwrap().to_vec(), (data.min_temp, data.max_temp, data.total, data.count)))
+        .collect::<std::collections::HashMap<_, _>>();
+
+    let a_stations = aggregates(a);
+    return a_stations.len() == b.stations().count() && a_stations == aggregates(b);
 }
 
-fn find_segment_splits(file: &File, num_segments: usize) -> Vec<(usize, usize)> {
+// k-way merge of station lists that are each already sorted by name, e.g. from workers that keep
+// their stations in sorted order. Stations with the same name are combined, and the result is
+// sorted without ever doing a global sort, only a heap holding the front of every list
+pub(crate) fn kway_merge_sorted(lists: Vec<Vec<StationData>>) -> Vec<StationData> {
+    // BinaryHeap is a max heap, so compare the other way around to pop the smallest name first.
+    // Ties go to the lower list index to keep the merge deterministic
+    struct Front {
+        data: StationData,
+        list: usize,
+    }
+    impl PartialEq for Front {
+        fn eq(&self, other: &Self) -> bool {
+            self.cmp(other) == std::cmp::Ordering::Equal
+        }
+    }
+    impl Eq for Front {}
+    impl PartialOrd for Front {
+        fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+    impl Ord for Front {
+        fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+            other.data.name.cmp(&self.data.name).then(other.list.cmp(&self.list))
+        }
+    }
+
+    let mut iters: Vec<_> = lists.into_iter().map(|list| list.into_iter()).collect();
+    let mut heap = BinaryHeap::with_capacity(iters.len());
+    for (list, iter) in iters.iter_mut().enumerate() {
+        if let Some(data) = iter.next() {
+            heap.push(Front { data, list });
+        }
+    }
+
+    let mut merged: Vec<StationData> = Vec::new();
+    while let Some(Front { data, list }) = heap.pop() {
+        if let Some(next) = iters[list].next() {
+            heap.push(Front { data: next, list });
+        }
+        match merged.last_mut() {
+            Some(last) if last.name == data.name => last.merge_from(data),
+            _ => merged.push(data),
+        }
+    }
+    return merged;
+}
+
+pub(crate) fn find_segment_splits(file: &File, num_segments: usize) -> Vec<(usize, usize)> {
     let file_len = file.metadata().unwrap().len() as usize;
+    return find_line_splits(file_len, num_segments, |buf, pos| file.read_at(buf, pos as u64).unwrap());
+}
+
+// the boundary search behind find_segment_splits, reading through read_at(buf, pos) so the
+// positioned reads here and v14_par's seek + read share it. read_at returns the number of bytes
+// read, 0 at EOF
+pub(crate) fn find_line_splits(file_len: usize, num_segments: usize, mut read_at: impl FnMut(&mut [u8], usize) -> usize) -> Vec<(usize, usize)> {
     let expected_segment_size = file_len / num_segments;
 
     let buf: &mut [u8] = &mut [0u8 ; 64];
@@ -66,11 +1046,21 @@ fn find_segment_splits(file: &File, num_segments: usize) -> Vec<(usize, usize)>
     let mut prev = 0;
     let mut split_indices = vec![];
     for i in 1..num_segments {
+        // with more segments than lines (tiny files) several search starts land in the same line,
+        // or past the last newline. Those splits all snap to the same boundary or to the end of
+        // the file, giving empty (start == end) segments rather than reads past EOF
         let search_start = i * expected_segment_size;
-        file.read_exact_at(buf, search_start as u64).unwrap();
-        let j = buf.iter().position(|c| *c == b'\n').unwrap();
-
-        let curr = search_start + j + 1;
+        // a line can be over 100 bytes, so the newline isn't always in the first read; keep
+        // reading forward until one turns up or the file ends
+        let mut read_pos = search_start;
+        let curr = loop {
+            let bytes_read = read_at(buf, read_pos);
+            match buf[..bytes_read].iter().position(|c| *c == b'\n') {
+                Some(j) => break read_pos + j + 1,
+                None if bytes_read == 0 || read_pos + bytes_read >= file_len => break file_len,
+                None => read_pos += bytes_read,
+            }
+        };
         split_indices.push((prev, curr));
         prev = curr;
     }
@@ -79,49 +1069,344 @@ fn find_segment_splits(file: &File, num_segments: usize) -> Vec<(usize, usize)>
     return split_indices;
 }
 
-fn scan_file_segment(file: &File, start_pos: usize, end_pos: usize) -> CustomHashMap {
-    const BUF_SIZE: usize = 16 * 1024 * 1024;
+// scans a single [start, end) byte range of the file on the calling thread, so the scan and merge
+// steps of the parallel pipeline can be exercised on their own. start and end have to be line
+// boundaries, like the ones find_segment_splits returns
+pub(crate) fn scan_segment(measurements_path: &str, start: u64, end: u64) -> CustomHashMap {
+    let file = open_measurements(measurements_path).unwrap_or_else(|e| panic!("{}", e));
+    return scan_file_segment(&file, start as usize, end as usize, Modulus::from_env(), KeyHash::from_env(), &RunOptions::default())
+        .unwrap_or_else(|e| panic!("{}", e));
+}
+
+fn scan_file_segment(file: &File, start_pos: usize, end_pos: usize, modulus: Modulus, key_hash: KeyHash, options: &RunOptions) -> Result<CustomHashMap, BrcError> {
+    let mut map = CustomHashMap::with_strategy(modulus, key_hash);
+    scan_file_segment_into(&mut map, file, start_pos, end_pos, options)?;
+    return Ok(map);
+}
+
+pub(crate) fn scan_file_segment_into(map: &mut CustomHashMap, file: &File, start_pos: usize, end_pos: usize, options: &RunOptions) -> Result<(), BrcError> {
+    let cancel = options.cancel.as_deref();
+    let field_order = options.field_order;
+    let decimal_sep = options.decimal_sep;
+    let input_unit = options.input_unit;
+
+    if !options.strict && options.collapse_runs {
+        return scan_collapsing_runs(map, file, start_pos, end_pos, options);
+    }
+
+    // keep the strict check out of the lenient hot loop entirely
+    if !options.strict {
+        let flow = scan_segment_with(file, start_pos, end_pos, cancel, |first, second| {
+            let (name_slice, temp_slice) = field_order.assign(first, second);
+            if !(1..=MAX_NAME_LEN).contains(&name_slice.len()) {
+                map.name_length_warnings += 1;
+            }
+            let temp = input_unit.to_celsius_tenths(parse_temp(temp_slice));
+            map.get_mut(name_slice).add_temp(temp, name_slice);
+            ControlFlow::Continue(())
+        });
+        if flow.is_break() {
+            return Err(BrcError::Cancelled);
+        }
+        return Ok(());
+    }
+
+    let mut error = None;
+    let flow = scan_segment_with(file, start_pos, end_pos, cancel, |first, second| {
+        let (name_slice, temp_slice) = field_order.assign(first, second);
+        match parse_temp_strict_with(name_slice, temp_slice, decimal_sep) {
+            Ok(temp) => {
+                let temp = input_unit.to_celsius_tenths(temp);
+                map.get_mut(name_slice).add_temp(temp, name_slice);
+                ControlFlow::Continue(())
+            }
+            Err(e) => {
+                error = Some(e);
+                ControlFlow::Break(())
+            }
+        }
+    });
+    return match error {
+        Some(e) => Err(e),
+        None if flow.is_break() => Err(BrcError::Cancelled),
+        None => Ok(()),
+    };
+}
+
+// the lenient scan with RunOptions::collapse_runs. The previous line is kept as an owned copy
+// since the slices on_line gets don't outlive the chunk they point into
+fn scan_collapsing_runs(map: &mut CustomHashMap, file: &File, start_pos: usize, end_pos: usize, options: &RunOptions) -> Result<(), BrcError> {
+    let field_order = options.field_order;
+    let input_unit = options.input_unit;
+
+    // (first field, second field) of the previous line, the slot it went to and its parsed temp
+    let mut prev_first = Vec::with_capacity(MAX_NAME_LEN);
+    let mut prev_second = Vec::with_capacity(8);
+    let mut prev_slot: Option<usize> = None;
+    let mut prev_temp = 0;
+    let mut prev_bad_name = false;
+
+    let flow = scan_segment_with(file, start_pos, end_pos, options.cancel.as_deref(), |first, second| {
+        // the lengths differ for most non-repeats, so the byte compare rarely runs in full
+        if let Some(slot) = prev_slot && first == prev_first.as_slice() && second == prev_second.as_slice() {
+            map.name_length_warnings += prev_bad_name as u64;
+            map.backing[slot].add_repeated_temp(prev_temp);
+            return ControlFlow::Continue(());
+        }
+
+        let (name_slice, temp_slice) = field_order.assign(first, second);
+        prev_bad_name = !(1..=MAX_NAME_LEN).contains(&name_slice.len());
+        map.name_length_warnings += prev_bad_name as u64;
+        prev_temp = input_unit.to_celsius_tenths(parse_temp(temp_slice));
+        let (slot, data) = map.get_mut_with_slot(name_slice);
+        data.add_temp(prev_temp, name_slice);
+
+        prev_slot = Some(slot);
+        prev_first.clear();
+        prev_first.extend_from_slice(first);
+        prev_second.clear();
+        prev_second.extend_from_slice(second);
+        ControlFlow::Continue(())
+    });
+    if flow.is_break() {
+        return Err(BrcError::Cancelled);
+    }
+    return Ok(());
+}
+
+// reads the segment in BUF_SIZE chunks and calls on_line with the (name, temp) slices of every
+// complete line, so other modes can reuse the scanning loop with their own aggregation. Returning
+// ControlFlow::Break from on_line, or setting cancel (checked once per chunk), stops the scan
+// early, in which case Break is returned.
+//
+// Unlike v14's BufReader loop there is no carry buffer here. Segments start on a line boundary, so
+// the only partial line in a chunk is the one cut off at its end, and since reads are positioned
+// the next chunk simply starts at that line's first byte again. Re-reading those few bytes is
+// cheaper than copying them into a carry and stitching. The carry is only needed on the streaming
+// path (stream.rs) where input can't be read twice
+#[inline(always)]
+pub(crate) fn scan_segment_with<F: FnMut(&[u8], &[u8]) -> ControlFlow<()>>(file: &File, start_pos: usize, end_pos: usize, cancel: Option<&AtomicBool>, mut on_line: F) -> ControlFlow<()> {
+    const BUF_SIZE: usize = SCAN_BUF_SIZE;
     let mut buf = vec![0u8; BUF_SIZE];
     let mut offset = start_pos;
 
-    let mut map = CustomHashMap::new();
-
     loop {
-        // read the next chunk
-        let bytes_read = file.read_at(&mut buf, offset as u64).unwrap();
-        if bytes_read < BUF_SIZE {
-            buf.truncate(bytes_read);
+        if let Some(cancel) = cancel && cancel.load(Ordering::Relaxed) {
+            return ControlFlow::Break(());
         }
 
+        // read the next chunk. A short read only shortens the valid part below, buf keeps its full
+        // size so the read after it can still fill BUF_SIZE bytes
+        let bytes_read = file.read_at(&mut buf, offset as u64).unwrap();
+
+        // only the bytes up to the end of this segment are ours, anything after belongs to the
+        // next segment (or is stale data from a previous read) and must never be scanned
+        let valid_len = bytes_read.min(end_pos - offset);
+        let reaches_end = valid_len == end_pos - offset;
+
         // main line reading loop
-        let mut line_start = 0;
-        loop {
-            let slice = &buf[line_start..];
-            if let Some(newline_pos) = find_char(slice, b'\n') {
-                let semicolon_pos = find_char(slice, b';').unwrap();
-
-                let name_slice = &slice[..semicolon_pos];
-                let temp_slice = &slice[semicolon_pos+1..newline_pos];
-                let temp = parse_temp(temp_slice);
-                map.get_mut(name_slice).add_temp(temp, name_slice);
+        let chunk = &buf[..valid_len];
+        let mut lines = LineIter::new(chunk);
+        for (name_slice, temp_slice) in lines.by_ref() {
+            if on_line(name_slice, temp_slice).is_break() {
+                return ControlFlow::Break(());
+            }
+        }
+        let mut line_start = lines.position();
 
-                line_start += newline_pos + 1;
-            } else {
-                break;
+        // the last line of the file might not end with a newline
+        if reaches_end && line_start < chunk.len() {
+            let line = &chunk[line_start..];
+            if let Some(semicolon_pos) = memchr(b';', line) {
+                if on_line(&line[..semicolon_pos], &line[semicolon_pos+1..]).is_break() {
+                    return ControlFlow::Break(());
+                }
             }
+            line_start = chunk.len();
         }
 
-        // advance offset and break when we've read the entire file segment
+        // advance offset to the start of the cut off line, the next read picks it up from there
         offset += line_start;
-        if offset >= end_pos {
+        if offset >= end_pos || bytes_read == 0 {
             break;
         }
     }
-    return map;
+    return ControlFlow::Continue(());
 }
 
+// the parallel scan behind sketch.rs's quantiles and histogram.rs's percentiles: every thread
+// keeps one accumulator per station (made by new_acc, fed temps in tenths through add), the
+// per-thread accumulators are combined by name with merge, and each station is printed as
+// name=format_values(acc) in the usual sorted {...} output
+pub(crate) fn run_per_station<A: Send>(
+    measurements_path: &str,
+    new_acc: impl Fn() -> A + Sync,
+    add: impl Fn(&mut A, i32) + Sync,
+    merge: impl Fn(&mut A, &A),
+    format_values: impl Fn(&A) -> String,
+) -> String {
+    let measurements_file = File::open(measurements_path).unwrap();
+
+    let split_indices = find_segment_splits(&measurements_file, NUM_SEGMENTS);
+
+    let per_thread: Vec<HashMap<Vec<u8>, A>> = thread::scope(|s| {
+        let handles: Vec<_> = split_indices
+            .into_iter()
+            .map(|(start, end)| {
+                let (file, new_acc, add) = (&measurements_file, &new_acc, &add);
+                s.spawn(move || {
+                    let mut accs: HashMap<Vec<u8>, A> = HashMap::new();
+                    let _ = scan_segment_with(file, start, end, None, |name_slice, temp_slice| {
+                        let temp = parse_temp(temp_slice);
+                        match accs.get_mut(name_slice) {
+                            Some(acc) => add(acc, temp),
+                            None => add(accs.entry(name_slice.to_vec()).or_insert_with(new_acc), temp),
+                        }
+                        ControlFlow::Continue(())
+                    });
+                    accs
+                })
+            })
+            .collect();
+        return handles.into_iter().map(|h| h.join().unwrap()).collect();
+    });
+
+    let mut merged: HashMap<Vec<u8>, A> = HashMap::new();
+    for accs in per_thread {
+        for (name, acc) in accs {
+            merge(merged.entry(name).or_insert_with(&new_acc), &acc);
+        }
+    }
+
+    let mut parts = merged
+        .iter()
+        .map(|(name, acc)| format!("{}={}", String::from_utf8_lossy(name), format_values(acc)))
+        .collect::<Vec<_>>();
+    parts.sort();
+
+    return "{".to_owned() + &parts.join(", ") + "}";
+}
+
+// yields the (name, temp) slices of each complete line in buf. A trailing partial line (no
+// newline yet) is not yielded, it's left in remainder() for the caller to stitch or re-read
+pub struct LineIter<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> LineIter<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+    // offset of the first byte not consumed by a yielded line
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+    pub fn remainder(&self) -> &'a [u8] {
+        &self.buf[self.pos..]
+    }
+}
+
+impl<'a> Iterator for LineIter<'a> {
+    type Item = (&'a [u8], &'a [u8]);
+
+    #[inline(always)]
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.buf.len() {
+            return None;
+        }
+        let (name_slice, temp_slice, next_start) = next_line(self.buf, self.pos)?;
+        self.pos = next_start;
+        return Some((name_slice, temp_slice));
+    }
+}
+
+// splits off the line starting at line_start, returning its (name, temp) slices and the start of
+// the next line, or None if the line isn't complete within chunk. The split is purely positional
+// on the first ;, so names that look like numbers ("12345;6.7") are still just names and
+// parse_temp only ever sees the bytes after the ;. The ; is found first with the
+// SIMD search, then the newline is at most a handful of bytes later (the temperature is <= 5 bytes)
+// so a plain byte loop finds it without rescanning the name like two separate searches would.
+//
+// This is the hottest loop in the crate, so by default it's written with unchecked indexing. The
+// "safe-scan" feature swaps in an identical implementation with normal bounds checks.
+#[cfg(not(feature = "safe-scan"))]
 #[inline(always)]
-fn find_char(buf: &[u8], target: u8) -> Option<usize> {
+fn next_line(chunk: &[u8], line_start: usize) -> Option<(&[u8], &[u8], usize)> {
+    // SAFETY: the caller guarantees line_start < chunk.len(), semicolon_pos comes from a search
+    // within chunk[line_start..] so line_start <= semicolon_pos < chunk.len(), and newline_pos is
+    // checked against chunk.len() before every read, so every index and range below is in bounds
+    unsafe {
+        let rest = chunk.get_unchecked(line_start..);
+        let semicolon_pos = line_start + find_char_unbounded(rest, b';')?;
+
+        let mut newline_pos = semicolon_pos + 1;
+        while newline_pos < chunk.len() {
+            if *chunk.get_unchecked(newline_pos) == b'\n' {
+                let name_slice = chunk.get_unchecked(line_start..semicolon_pos);
+                let temp_slice = chunk.get_unchecked(semicolon_pos+1..newline_pos);
+                return Some((name_slice, temp_slice, newline_pos + 1));
+            }
+            newline_pos += 1;
+        }
+        return None;
+    }
+}
+
+#[cfg(feature = "safe-scan")]
+#[inline(always)]
+fn next_line(chunk: &[u8], line_start: usize) -> Option<(&[u8], &[u8], usize)> {
+    let semicolon_pos = line_start + find_char_unbounded(&chunk[line_start..], b';')?;
+
+    let mut newline_pos = semicolon_pos + 1;
+    while newline_pos < chunk.len() {
+        if chunk[newline_pos] == b'\n' {
+            let name_slice = &chunk[line_start..semicolon_pos];
+            let temp_slice = &chunk[semicolon_pos+1..newline_pos];
+            return Some((name_slice, temp_slice, newline_pos + 1));
+        }
+        newline_pos += 1;
+    }
+    return None;
+}
+
+// find_char only looks at the first 48 bytes when the buffer is long enough, names can be up to
+// 100 bytes so fall back to memchr over the rest
+#[inline(always)]
+pub(crate) fn find_char_unbounded(buf: &[u8], target: u8) -> Option<usize> {
+    match find_char(buf, target) {
+        Some(idx) => Some(idx),
+        None if buf.len() > 48 => memchr(target, &buf[48..]).map(|idx| 48 + idx),
+        None => None,
+    }
+}
+
+// one forward pass over the line starting at offset: finds the ;, then parses the temperature
+// digits while looking for the \n, so the temp bytes are only touched once. Returns the name,
+// the temp in tenths and the offset of the next line, or None if buf has no complete line left.
+// Like parse_temp, ".5" and "-.5" come out as 5 and -5
+pub(crate) fn scan_line(buf: &[u8], offset: usize) -> Option<(&[u8], i32, usize)> {
+    let semicolon_pos = offset + find_char_unbounded(&buf[offset..], b';')?;
+
+    let mut pos = semicolon_pos + 1;
+    let negative = buf.get(pos) == Some(&b'-');
+    let mut temp = 0;
+    while pos < buf.len() {
+        let c = buf[pos];
+        if c == b'\n' {
+            let name_slice = &buf[offset..semicolon_pos];
+            return Some((name_slice, if negative { -temp } else { temp }, pos + 1));
+        }
+        if c.is_ascii_digit() {
+            temp = temp.saturating_mul(10).saturating_add((c - b'0') as i32);
+        }
+        pos += 1;
+    }
+    return None;
+}
+
+#[inline(always)]
+pub(crate) fn find_char(buf: &[u8], target: u8) -> Option<usize> {
     if buf.len() >= 48 {
         let first = u8x16::from_slice(&buf[..16]);
         if let Some(idx) = first_match_in_u8x16(first, target) {
@@ -152,27 +1437,78 @@ fn first_match_in_u8x16(v: u8x16, target: u8) -> Option<usize> {
     }
 }
 
+// drops a trailing \r, so a temperature from a \r\n file has the length the format checks and
+// the fractional digit count expect. parse_temp and scan_line skip it like any other non-digit
 #[inline(always)]
-fn parse_temp(line: &[u8]) -> i32 {
-    let mut temp = 0;
+pub(crate) fn trim_cr(temp_slice: &[u8]) -> &[u8] {
+    return temp_slice.strip_suffix(b"\r").unwrap_or(temp_slice);
+}
+
+#[inline(always)]
+// saturates instead of overflowing on malformed temps with more digits than an i32 holds. Only
+// digits count, so a value without an integer digit (".5", "-.5", as some exports write them)
+// reads the same as "0.5"/"-0.5"
+pub(crate) fn parse_temp(line: &[u8]) -> i32 {
+    let mut temp: i32 = 0;
     for c in line {
         if c.is_ascii_digit() {
-            temp *= 10;
-            temp += (c - b'0') as i32
+            temp = temp.saturating_mul(10).saturating_add((c - b'0') as i32);
         }
     }
-    if line[0] == b'-' {
+    if line.first() == Some(&b'-') {
         temp *= -1;
     }
     return temp;
 }
 
-fn format_output(map: &CustomHashMap) -> String {
+// checks the temperature is in the spec's format (-?d?d.d) before parsing it, so a truncated or
+// garbled line is reported instead of turning into a phantom reading. A missing integer digit
+// (".5", "-.5") is accepted as 0.5/-0.5, the fractional digit is still required
+pub(crate) fn parse_temp_strict(name: &[u8], line: &[u8]) -> Result<i32, BrcError> {
+    return parse_temp_strict_with(name, line, b'.');
+}
+
+pub(crate) fn parse_temp_strict_with(name: &[u8], line: &[u8], decimal_sep: u8) -> Result<i32, BrcError> {
+    let line = trim_cr(line);
+    let malformed = |reason| BrcError::MalformedLine { name: name.to_vec(), temp: line.to_vec(), reason };
+
+    if name.is_empty() {
+        return Err(malformed("empty station name"));
+    }
+    if name.len() > MAX_NAME_LEN {
+        return Err(malformed("station name longer than 100 bytes"));
+    }
+
+    if line.is_empty() {
+        return Err(malformed("empty temperature"));
+    }
+    let digits = match line[0] {
+        b'-' => &line[1..],
+        _ => line,
+    };
+    let (int_part, frac_part) = match digits.iter().position(|c| *c == decimal_sep) {
+        Some(dot_pos) => (&digits[..dot_pos], &digits[dot_pos+1..]),
+        None => return Err(malformed("missing decimal point")),
+    };
+    if int_part.len() > 2 && int_part.iter().all(|c| c.is_ascii_digit()) {
+        return Err(malformed("too many integer digits"));
+    }
+    if int_part.len() > 2 || !int_part.iter().all(|c| c.is_ascii_digit()) {
+        return Err(malformed("expected at most two integer digits"));
+    }
+    if frac_part.len() != 1 || !frac_part[0].is_ascii_digit() {
+        return Err(malformed("expected exactly one fractional digit"));
+    }
+
+    return Ok(parse_temp(line));
+}
+
+pub(crate) fn format_output(map: &CustomHashMap) -> String {
 
     let mut parts = map.backing
         .iter()
         .filter(|data| data.count > 0)
-        .map(|data| data.format_data_point())
+        .map(|data| data.format_data_point_with(map.ascii_hint, map.fractional_digits))
         .collect::<Vec<_>>();
     parts.sort();
 
@@ -181,15 +1517,260 @@ fn format_output(map: &CustomHashMap) -> String {
     return result;
 }
 
+// format_output without the final sort, for consumers that sort downstream anyway. Stations come
+// out in slot order, which depends on the hash strategy and the table size, so the order is
+// unspecified and may change between versions; only the set of entries matches format_output
+pub(crate) fn format_output_unsorted(map: &CustomHashMap) -> String {
+    let parts = map.backing
+        .iter()
+        .filter(|data| data.count > 0)
+        .map(|data| data.format_data_point_with(map.ascii_hint, map.fractional_digits))
+        .collect::<Vec<_>>();
+
+    return "{".to_owned() + &parts.join(", ") + "}";
+}
+
+// format_output with every name passed through transform first. Sorted like format_output by the
+// transformed names, or by the original names if !sort_by_display_name. Two names may transform
+// to the same display name, they still show up as separate entries
+pub(crate) fn format_output_display(map: &CustomHashMap, transform: fn(&[u8]) -> Vec<u8>, sort_by_display_name: bool) -> String {
+    let mut stations = map.stations().collect::<Vec<_>>();
+    if !sort_by_display_name {
+        stations.sort_by(|a, b| a.name.cmp(&b.name));
+    }
+
+    let mut parts = stations
+        .iter()
+        .map(|data| {
+            let display_name = transform(data.name.as_deref().unwrap());
+            data.format_data_point_named(&String::from_utf8_lossy(&display_name), map.fractional_digits)
+        })
+        .collect::<Vec<_>>();
+    if sort_by_display_name {
+        parts.sort();
+    }
 
+    return "{".to_owned() + &parts.join(", ") + "}";
+}
 
+// scans the file and streams the grouped output into writer, see write_output_grouped
+pub fn run_grouped<W: Write>(measurements_path: &str, writer: &mut W) -> Result<(), BrcError> {
+    let (map, _stats) = aggregate_file(measurements_path, &RunOptions::default())?;
+    write_output_grouped(&map, writer)?;
+    return Ok(());
+}
+
+// scans the file and streams the canonical output into writer, see write_output_streamed
+pub fn run_streamed<W: Write>(measurements_path: &str, writer: &mut W) -> Result<(), BrcError> {
+    let (map, _stats) = aggregate_file(measurements_path, &RunOptions::default())?;
+    write_output_streamed(&map, writer)?;
+    return Ok(());
+}
+
+// format_output's bytes, written entry by entry instead of joined into one String
+pub(crate) fn write_output_streamed<W: Write>(map: &CustomHashMap, writer: &mut W) -> io::Result<()> {
+    return write_stations_streamed(map.stations(), map.ascii_hint, map.fractional_digits, writer);
+}
+
+// the {name=min/mean/max, ...} output for any set of stations. Only references to the stations
+// are sorted and each entry is formatted just before it's written, so peak memory is the sorted
+// index plus one entry rather than every formatted entry and their join. Takes an iterator rather
+// than a map so station sets bigger than a CustomHashMap's table can be written too
+pub(crate) fn write_stations_streamed<'a, W: Write>(stations: impl Iterator<Item = &'a StationData>, ascii_hint: bool, fractional_digits: u32, writer: &mut W) -> io::Result<()> {
+    let mut stations: Vec<_> = stations.filter(|data| data.count > 0).collect();
+    // format_output sorts the formatted "name=..." strings, so compare the names with the '='
+    // after them: "Ab =" sorts before "Ab=" even though "Ab" is the shorter name
+    stations.sort_by(|a, b| {
+        let (a, b) = (a.name.as_deref().unwrap(), b.name.as_deref().unwrap());
+        a.iter().chain(b"=").cmp(b.iter().chain(b"="))
+    });
+
+    writer.write_all(b"{")?;
+    for (i, data) in stations.iter().enumerate() {
+        if i > 0 {
+            writer.write_all(b", ")?;
+        }
+        writer.write_all(data.format_data_point_with(ascii_hint, fractional_digits).as_bytes())?;
+    }
+    writer.write_all(b"}")?;
+    return writer.flush();
+}
+
+// the output split into one line per first character of the station name:
+//      A: Abha=-23.0/18.0/59.2, Accra=...
+//      B: ...
+// Groups come in order of their first character (code point order is byte order in UTF-8), and
+// stations within a group in byte order of their name. Each group is sorted, written and flushed
+// before the next one is formatted, so only references to the stations are held and never the
+// whole output string, which matters once the station count goes past the spec's 10,000
+pub(crate) fn write_output_grouped<W: Write>(map: &CustomHashMap, writer: &mut W) -> io::Result<()> {
+    let mut groups: BTreeMap<char, Vec<&StationData>> = BTreeMap::new();
+    for data in map.stations() {
+        let name = data.name.as_deref().unwrap();
+        // only the first character is needed, which is at most 4 bytes
+        let first = String::from_utf8_lossy(&name[..name.len().min(4)]).chars().next().unwrap_or_default();
+        groups.entry(first).or_default().push(data);
+    }
+
+    for (first, mut stations) in groups {
+        stations.sort_by(|a, b| a.name.cmp(&b.name));
+        write!(writer, "{}: ", first)?;
+        for (i, data) in stations.iter().enumerate() {
+            if i > 0 {
+                writer.write_all(b", ")?;
+            }
+            writer.write_all(data.format_data_point_with(map.ascii_hint, map.fractional_digits).as_bytes())?;
+        }
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+    }
+    return Ok(());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    // the canonical {name=min/mean/max, ...} output
+    Braced,
+    // {"name":{"min":..,"mean":..,"max":..},...}, sorted by name like Braced
+    Json,
+}
+
+// scans the file once and renders the merged map in every requested format
+pub fn run_multi_format(measurements_path: &str, formats: &[Format]) -> Vec<(Format, String)> {
+    let (map, _stats) = aggregate_file(measurements_path, &RunOptions::default())
+        .unwrap_or_else(|e| panic!("{}", e));
+
+    return formats
+        .iter()
+        .map(|format| {
+            let output = match format {
+                Format::Braced => format_output(&map),
+                Format::Json => format_output_json(&map),
+            };
+            (*format, output)
+        })
+        .collect();
+}
+
+pub(crate) fn format_output_json(map: &CustomHashMap) -> String {
+    let mut stations = map.stations().collect::<Vec<_>>();
+    stations.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let digits = map.fractional_digits;
+    let parts = stations
+        .iter()
+        .map(|data| format!("\"{}\":{{\"min\":{},\"mean\":{},\"max\":{}}}",
+            json_escape(&String::from_utf8_lossy(data.name.as_deref().unwrap())),
+            format_fixed(data.min_temp as i64, digits),
+            format_fixed(data.rounded_mean(), digits),
+            format_fixed(data.max_temp as i64, digits)
+        ))
+        .collect::<Vec<_>>();
+
+    return "{".to_owned() + &parts.join(",") + "}";
+}
+
+fn json_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    return escaped;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SortKey {
+    // byte-lexicographic, like format_output: "Station10" comes before "Station2"
+    #[default]
+    Name,
+    // like Name, but runs of ASCII digits compare as numbers: "Station2" before "Station10"
+    NaturalName,
+    Mean,
+    Min,
+    Max,
+    Count,
+}
+
+// same output format as format_output, but ordered by the given field (ascending), with ties
+// broken by name so the output stays deterministic
+pub(crate) fn format_output_sorted_by(map: &CustomHashMap, key: SortKey) -> String {
+    if key == SortKey::Name {
+        return format_output(map);
+    }
+
+    let mut stations = map.backing
+        .iter()
+        .filter(|data| data.count > 0)
+        .collect::<Vec<_>>();
+
+    stations.sort_by(|a, b| {
+        let by_key = match key {
+            SortKey::Name => unreachable!(),
+            SortKey::NaturalName => natural_cmp(a.name.as_deref().unwrap(), b.name.as_deref().unwrap()),
+            SortKey::Mean => (a.total as f64 / a.count as f64).total_cmp(&(b.total as f64 / b.count as f64)),
+            SortKey::Min => a.min_temp.cmp(&b.min_temp),
+            SortKey::Max => a.max_temp.cmp(&b.max_temp),
+            SortKey::Count => a.count.cmp(&b.count),
+        };
+        by_key.then_with(|| a.name.cmp(&b.name))
+    });
+
+    let parts = stations
+        .iter()
+        .map(|data| data.format_data_point_with(map.ascii_hint, map.fractional_digits))
+        .collect::<Vec<_>>();
+
+    let result = "{".to_owned() + &parts.join(", ") + "}";
+
+    return result;
+}
+
+// natural order over name bytes: digit runs are compared by numeric value (by length once leading
+// zeros are skipped, so any number of digits works), everything else byte by byte. Numbers that
+// only differ in leading zeros ("7" and "007") compare equal here, the caller's tie break on the
+// raw bytes keeps the order deterministic
+pub(crate) fn natural_cmp(a: &[u8], b: &[u8]) -> std::cmp::Ordering {
+    let digit_run = |s: &[u8], from: usize| from + s[from..].iter().take_while(|c| c.is_ascii_digit()).count();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i].is_ascii_digit() && b[j].is_ascii_digit() {
+            let (a_end, b_end) = (digit_run(a, i), digit_run(b, j));
+            let a_num = &a[i + a[i..a_end].iter().take_while(|c| **c == b'0').count()..a_end];
+            let b_num = &b[j + b[j..b_end].iter().take_while(|c| **c == b'0').count()..b_end];
+            let by_value = a_num.len().cmp(&b_num.len()).then_with(|| a_num.cmp(b_num));
+            if by_value.is_ne() {
+                return by_value;
+            }
+            (i, j) = (a_end, b_end);
+        } else {
+            if a[i] != b[j] {
+                return a[i].cmp(&b[j]);
+            }
+            (i, j) = (i + 1, j + 1);
+        }
+    }
+    return (a.len() - i).cmp(&(b.len() - j));
+}
+
+
+
+// min_temp/max_temp start at i32::MAX/i32::MIN as sentinels, so a slot is only considered
+// occupied once count > 0. A reading of exactly 0.0 still goes through add_temp like any other
+// (count += 1, total += 0), so a station that only ever reads 0.0 reports 0.0/0.0/0.0.
+// total is an i64: a billion -99.9 readings for one station sum to about -10^12 tenths, far past
+// i32::MAX, while count still fits a u32
 #[derive(Debug, Clone)]
-struct StationData {
-    min_temp: i32,
-    max_temp: i32,
-    total: i32,
-    count: u32,
-    name: Option<Vec<u8>>,
+pub(crate) struct StationData {
+    pub(crate) min_temp: i32,
+    pub(crate) max_temp: i32,
+    pub(crate) total: i64,
+    pub(crate) count: u32,
+    pub(crate) name: Option<Vec<u8>>,
 }
 
 impl StationData {
@@ -203,18 +1784,38 @@ impl StationData {
             name: None
         }
     }
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
     #[inline(always)]
     pub fn add_temp(&mut self, temp: i32, name: &[u8]) {
         self.min_temp = self.min_temp.min(temp);
         self.max_temp = self.max_temp.max(temp);
-        self.total += temp;
+        self.total += temp as i64;
         self.count += 1;
         if self.name.is_none() {
             self.name = Some(name.to_vec());
         }
     }
+    // builds a station by feeding temps (in tenths) through add_temp, handy for fixtures
+    pub fn from_samples(name: &[u8], temps: &[i32]) -> Self {
+        let mut data = Self::new();
+        for temp in temps {
+            data.add_temp(*temp, name);
+        }
+        return data;
+    }
+    // add_temp for a station that's known to have its name already, the run-length fast path
+    #[inline(always)]
+    pub fn add_repeated_temp(&mut self, temp: i32) {
+        self.min_temp = self.min_temp.min(temp);
+        self.max_temp = self.max_temp.max(temp);
+        self.total += temp as i64;
+        self.count += 1;
+    }
     #[inline(always)]
     pub fn merge_with(&mut self, other: &StationData) {
+        // an empty other still has the sentinel min/max, which min()/max() ignore
         self.min_temp = self.min_temp.min(other.min_temp);
         self.max_temp = self.max_temp.max(other.max_temp);
         self.total += other.total;
@@ -223,37 +1824,282 @@ impl StationData {
             self.name = other.name.clone();
         }
     }
+    // like merge_with, but takes ownership of other so its name can be moved rather than cloned
+    #[inline(always)]
+    pub fn merge_from(&mut self, other: StationData) {
+        self.min_temp = self.min_temp.min(other.min_temp);
+        self.max_temp = self.max_temp.max(other.max_temp);
+        self.total += other.total;
+        self.count += other.count;
+        if self.name.is_none() {
+            self.name = other.name;
+        }
+    }
     pub fn format_data_point(&self) -> String {
-        return format!("{}={:.1}/{:.1}/{:.1}", 
-            String::from_utf8(self.name.clone().unwrap()).unwrap(), 
-            0.1 * self.min_temp as f32, 
-            0.1 * self.total as f32 / self.count as f32, 
-            0.1 * self.max_temp as f32
+        return self.format_data_point_hinted(false);
+    }
+    pub fn format_data_point_hinted(&self, ascii_hint: bool) -> String {
+        return self.format_data_point_with(ascii_hint, 1);
+    }
+    // fractional_digits is the resolution the temps were stored in, 1 means tenths
+    pub fn format_data_point_with(&self, ascii_hint: bool, fractional_digits: u32) -> String {
+        let name = self.name.as_deref().unwrap();
+        // the hint only covers the start of the file, so each name is still checked, but
+        // is_ascii is a lot cheaper than full UTF-8 validation
+        let name = if ascii_hint && name.is_ascii() {
+            // SAFETY: ASCII is always valid UTF-8
+            unsafe { std::str::from_utf8_unchecked(name) }
+        } else {
+            std::str::from_utf8(name).unwrap()
+        };
+        return self.format_data_point_named(name, fractional_digits);
+    }
+    // formats the aggregates under the given name instead of the stored one
+    pub fn format_data_point_named(&self, name: &str, fractional_digits: u32) -> String {
+        // a station with a name but no valid readings would otherwise divide by zero and print
+        // NaN next to the sentinel min/max. format_output already leaves these out
+        if self.count == 0 {
+            return format!("{}=n/a", name);
+        }
+        return format!("{}={}/{}/{}",
+            name,
+            format_fixed(self.min_temp as i64, fractional_digits),
+            format_fixed(self.rounded_mean(), fractional_digits),
+            format_fixed(self.max_temp as i64, fractional_digits)
         );
     }
+    // the mean in the stored resolution, see mean_half_up
+    pub fn rounded_mean(&self) -> i64 {
+        return mean_half_up(self.total, self.count);
+    }
+}
+
+// total / count rounded to the nearest integer, with an exact half always going up towards
+// positive infinity like the reference's Math.round: 12.5 -> 13 but -12.5 -> -12 (so 1.25 degrees
+// prints 1.3 and -1.25 prints -1.2). That's floor(total / count + 1/2), done as
+// floor((2 * total + count) / (2 * count)) so no float ever sees it; div_euclid floors for
+// negative totals where / would truncate towards zero. A mean of -0.5 tenths rounds to 0, which
+// formats as 0.0 with no sign to get wrong. count has to be at least 1
+pub(crate) fn mean_half_up(total: i64, count: u32) -> i64 {
+    let count = count as i64;
+    return (2 * total + count).div_euclid(2 * count);
+}
+
+// formats a value stored in units of 10^-fractional_digits as d.d (d.dd, ...) straight from the
+// integer digits. Float formatting rounds ties to even on the binary value and can print -0.0,
+// the reference output does neither
+pub(crate) fn format_fixed(value: i64, fractional_digits: u32) -> String {
+    if fractional_digits == 0 {
+        return value.to_string();
+    }
+    let scale = 10u64.pow(fractional_digits);
+    let sign = if value < 0 { "-" } else { "" };
+    let abs = value.unsigned_abs();
+    return format!("{}{}.{:0width$}", sign, abs / scale, abs % scale, width = fractional_digits as usize);
+}
+
+// how a hash is reduced to a slot index. v11-v14 used a prime sized table with % (plus the magic
+// seed), v15+ a power of two table with &. They collide differently, so both are kept around to
+// benchmark against each other; pick one with BRC_MODULUS=prime|pow2 (defaults to pow2)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Modulus {
+    Prime,
+    #[default]
+    PowerOfTwo,
+}
+
+impl Modulus {
+    pub fn from_env() -> Self {
+        match std::env::var("BRC_MODULUS").as_deref() {
+            Ok("prime") => Modulus::Prime,
+            _ => Modulus::PowerOfTwo,
+        }
+    }
+    pub fn table_size(&self) -> usize {
+        match self {
+            Modulus::Prime => 12_289,
+            Modulus::PowerOfTwo => 32_768,
+        }
+    }
+}
+
+// how a station name is turned into a u64 before mixing. Sampled only looks at 6 bytes plus the
+// length, which is fast but easy to collide on adversarial names; Full hashes every byte with
+// FNV-1a. SampledAt samples the bytes at the given positions instead of the fixed ones, to try out
+// positions that tell a particular set of names apart better. Pick one with
+// BRC_HASH=sampled|full|sampled:<6 positions> (defaults to sampled), e.g. sampled:0,4,5,-3,-2,-1
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyHash {
+    #[default]
+    Sampled,
+    Full,
+    SampledAt(KeySampling),
+}
+
+impl KeyHash {
+    pub fn from_env() -> Self {
+        match std::env::var("BRC_HASH").as_deref() {
+            Ok("full") => KeyHash::Full,
+            Ok(spec) if let Some(sampling) = spec.strip_prefix("sampled:").and_then(KeySampling::parse) => KeyHash::SampledAt(sampling),
+            _ => KeyHash::Sampled,
+        }
+    }
+}
+
+// the 6 name bytes a sampled key is made of, next to the name's length. A position >= 0 counts
+// from the start of the name, a negative one from the end (-1 is the last byte), and a position
+// past either end of the name samples a 0. The default is the fixed sampling of get_u64_key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeySampling {
+    pub positions: [i8; 6],
+}
+
+impl Default for KeySampling {
+    fn default() -> Self {
+        Self { positions: [0, 1, 2, -3, -2, -1] }
+    }
+}
+
+impl KeySampling {
+    // 6 comma separated positions, None if there aren't exactly 6 or one isn't a number
+    pub fn parse(spec: &str) -> Option<Self> {
+        let positions: Vec<i8> = spec.split(',').map(|pos| pos.trim().parse().ok()).collect::<Option<_>>()?;
+        return Some(Self { positions: positions.try_into().ok()? });
+    }
 }
 
-struct CustomHashMap {
+pub(crate) struct CustomHashMap {
     backing: Vec<StationData>,
+    // slots handed out by get_mut, so clear() doesn't have to touch the whole table
+    occupied: Vec<u32>,
+    modulus: Modulus,
+    key_hash: KeyHash,
+    // set from detect_ascii, lets formatting skip UTF-8 validation of the names
+    ascii_hint: bool,
+    // resolution of the stored temps, set by run_auto
+    fractional_digits: u32,
+    // lenient mode lines with a name outside 1..=MAX_NAME_LEN bytes
+    name_length_warnings: u64,
+    #[cfg(feature = "stats")]
+    probe_steps: u64,
+    #[cfg(feature = "stats")]
+    max_probe_chain: u32,
 }
 
 impl CustomHashMap {
     pub fn new() -> Self {
+        Self::with_modulus(Modulus::default())
+    }
+    pub fn with_modulus(modulus: Modulus) -> Self {
+        Self::with_strategy(modulus, KeyHash::default())
+    }
+    pub fn with_strategy(modulus: Modulus, key_hash: KeyHash) -> Self {
         Self {
-            backing: vec![StationData::new() ; 32_768]
+            backing: vec![StationData::new() ; modulus.table_size()],
+            occupied: Vec::new(),
+            modulus,
+            key_hash,
+            ascii_hint: false,
+            fractional_digits: 1,
+            name_length_warnings: 0,
+            #[cfg(feature = "stats")]
+            probe_steps: 0,
+            #[cfg(feature = "stats")]
+            max_probe_chain: 0,
+        }
+    }
+    // all stations with at least one reading, in slot order
+    pub fn stations(&self) -> impl Iterator<Item = &StationData> {
+        self.backing.iter().filter(|data| data.count > 0)
+    }
+    // sorted and deduplicated, get_mut can record a slot more than once if it's looked up again
+    // before a name is stored in it
+    pub fn occupied_slots(&self) -> Vec<usize> {
+        let mut slots: Vec<_> = self.occupied.iter().map(|index| *index as usize).collect();
+        slots.sort_unstable();
+        slots.dedup();
+        return slots;
+    }
+    pub fn backing(&self) -> &[StationData] {
+        return &self.backing;
+    }
+    // the slot holding key, or the empty slot its probe chain ends at if key isn't in the map yet,
+    // plus the number of slots holding other names that were stepped over. Linear probing: the
+    // seed only keeps the stations of one dataset apart and any other pair can still collide, so a
+    // slot belongs to the first name stored in it and whichever of two colliding stations comes
+    // second lives further down the table. The name compare on every lookup costs about 10% of a
+    // run. v11-v14 and v16 probe the same way
+    #[inline(always)]
+    pub(crate) fn probe(&self, key: &[u8]) -> (usize, u32) {
+        let mut index = self.slot_index(key);
+        let mut chain = 0;
+        while let Some(name) = &self.backing[index].name && name.as_slice() != key {
+            chain += 1;
+            assert!((chain as usize) < self.backing.len(), "hash table full, more than {} distinct station names", self.backing.len());
+            index = (index + 1) % self.backing.len();
+        }
+        return (index, chain);
+    }
+    // the slot a probe for key starts at
+    #[inline(always)]
+    pub(crate) fn slot_index(&self, key: &[u8]) -> usize {
+        let u64_key = match self.key_hash {
+            KeyHash::Sampled => get_u64_key(key),
+            KeyHash::Full => full_hash(key),
+            KeyHash::SampledAt(sampling) => get_u64_key_at(key, &sampling),
+        };
+        return match self.modulus {
+            Modulus::Prime => mix64(u64_key).wrapping_mul(384) as usize % 12_289, // 384 is a magic seed
+            Modulus::PowerOfTwo => mix64(u64_key) as usize & (32_768 - 1),
+        };
+    }
+    pub fn into_stations(self) -> impl Iterator<Item = StationData> {
+        self.backing.into_iter().filter(|data| data.count > 0)
+    }
+    // resets the map to empty without reallocating the backing table, so maps can be reused
+    // across runs
+    pub fn clear(&mut self) {
+        for index in self.occupied.drain(..) {
+            self.backing[index as usize].reset();
+        }
+        self.name_length_warnings = 0;
+        #[cfg(feature = "stats")]
+        {
+            self.probe_steps = 0;
+            self.max_probe_chain = 0;
         }
     }
     #[inline(always)]
     pub fn get_mut(&mut self, key: &[u8]) -> &mut StationData {
-        let u64_key = get_u64_key(key);
-        let hashed_key = mix64(u64_key);
-        let index = hashed_key as usize & (32_768 - 1);
-        return &mut self.backing[index];
+        return self.get_mut_with_slot(key).1;
+    }
+    // get_mut that also returns the slot, so a caller can come back to it without hashing again
+    #[inline(always)]
+    pub(crate) fn get_mut_with_slot(&mut self, key: &[u8]) -> (usize, &mut StationData) {
+        let (index, _chain) = self.probe(key);
+
+        #[cfg(feature = "stats")]
+        {
+            self.probe_steps += _chain as u64;
+            self.max_probe_chain = self.max_probe_chain.max(_chain);
+        }
+
+        if self.backing[index].name.is_none() {
+            self.occupied.push(index as u32);
+        }
+
+        return (index, &mut self.backing[index]);
     }
 }
 
 #[inline(always)]
 fn get_u64_key(bytes: &[u8]) -> u64 {
+    // 1 and 2 byte names are valid but don't have 3 bytes to sample at each end
+    if bytes.len() < 3 {
+        let mut padded = [0u8; 3];
+        padded[..bytes.len()].copy_from_slice(bytes);
+        return u64::from_le_bytes([padded[0], padded[1], padded[2], 0, 0, 0, bytes.len() as u8, 0]);
+    }
     let key = u64::from_le_bytes([
         bytes[0],
         bytes[1],
@@ -267,8 +2113,38 @@ fn get_u64_key(bytes: &[u8]) -> u64 {
     return key;
 }
 
+// get_u64_key with the sampled positions picked by sampling. Names under 3 bytes still go through
+// get_u64_key's padding, their bytes and length already make the key unique
+#[inline(always)]
+fn get_u64_key_at(bytes: &[u8], sampling: &KeySampling) -> u64 {
+    if bytes.len() < 3 {
+        return get_u64_key(bytes);
+    }
+    let mut key = [0u8; 8];
+    for (sample, &pos) in key.iter_mut().zip(&sampling.positions) {
+        let index = match pos {
+            0.. => pos as usize,
+            _ => bytes.len().wrapping_sub(pos.unsigned_abs() as usize),
+        };
+        *sample = bytes.get(index).copied().unwrap_or(0);
+    }
+    key[6] = bytes.len() as u8;
+    return u64::from_le_bytes(key);
+}
+
+// FNV-1a over every byte of the name, slower than get_u64_key but every byte matters
+#[inline(always)]
+fn full_hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    return hash;
+}
+
 #[inline(always)]
-fn mix64(mut x: u64) -> u64 {
+pub(crate) fn mix64(mut x: u64) -> u64 {
     x ^= x >> 30;
     x = x.wrapping_mul(0xbf58476d1ce4e5b9);
     x ^= x >> 27;
@@ -12,10 +12,12 @@
 //      - 4s, reader spends 98% of time on pread
 
 
-use std::{fs::File, i32, os::unix::fs::FileExt, simd::{Simd, cmp::SimdPartialEq, u8x16}, sync::{Arc, Condvar, Mutex, atomic::{AtomicBool, Ordering}}, thread, vec};
+use std::{fs::File, i32, os::unix::fs::FileExt, simd::{Simd, cmp::SimdPartialEq, u8x16}, sync::{Arc, Condvar, Mutex, atomic::{AtomicBool, AtomicUsize, Ordering}}, thread, time::{Duration, Instant}, vec};
 
 use memchr::memchr;
 
+use crate::v15::{format_fixed, mean_half_up};
+
 
 // thin wrapper around a buf that contains length data
 struct Chunk {
@@ -27,7 +29,9 @@ struct Chunk {
 struct Pool<T> {
     inner: Mutex<Vec<T>>,
     cv: Condvar,
-    closed: AtomicBool
+    closed: AtomicBool,
+    // most items the pool held at once, only ever grows
+    max_len: AtomicUsize,
 }
 
 impl<T> Pool<T> {
@@ -36,6 +40,7 @@ impl<T> Pool<T> {
             inner: Mutex::new(Vec::new()),
             cv: Condvar::new(),
             closed: false.into(),
+            max_len: 0.into(),
         }
     }
     pub fn take(&self) -> Option<T> {
@@ -57,6 +62,7 @@ impl<T> Pool<T> {
     pub fn put(&self, returned: T) {
         let mut guard = self.inner.lock().unwrap();
         guard.push(returned);
+        self.max_len.fetch_max(guard.len(), Ordering::Relaxed);
         self.cv.notify_one();
     }
     pub fn close(&self) {
@@ -90,12 +96,17 @@ fn reader_thread(file: File, empty_bufs: Arc<Pool<Box<[u8]>>>, full_chunks: Arc<
     full_chunks.close();
 }
 
-fn worker_thread(empty_bufs: Arc<Pool<Box<[u8]>>>, full_chunks: Arc<Pool<Chunk>>) -> CustomHashMap {
+// returns the worker's map, how long it spent waiting on full_chunks and when it ran out of work
+fn worker_thread(empty_bufs: Arc<Pool<Box<[u8]>>>, full_chunks: Arc<Pool<Chunk>>) -> (CustomHashMap, Duration, Instant) {
     let mut map = CustomHashMap::new();
+    let mut waiting = Duration::ZERO;
 
     loop {
         // get buf to process
-        let chunk = match full_chunks.take() {
+        let wait_start = Instant::now();
+        let taken = full_chunks.take();
+        waiting += wait_start.elapsed();
+        let chunk = match taken {
             Some(chunk) => chunk,
             None => break
         };
@@ -121,11 +132,46 @@ fn worker_thread(empty_bufs: Arc<Pool<Box<[u8]>>>, full_chunks: Arc<Pool<Chunk>>
         empty_bufs.put(chunk.buf);
     }
 
-    return map;
+    return (map, waiting, Instant::now());
+}
+
+
+// numbers for sizing NUM_BUFS: a max_full_chunks_depth that sits at NUM_BUFS means the reader is
+// outrunning the workers and the extra buffers are just read-ahead held in memory, one that stays
+// near 1 means the workers are waiting on the reader
+#[derive(Debug, Clone, Default)]
+pub struct PipelineMetrics {
+    pub max_full_chunks_depth: usize,
+    // one entry per worker. Idle workers next to busy ones mean the chunks aren't evenly heavy
+    // (skewed line lengths, or too few chunks to go around), smaller chunks spread the work better
+    pub workers: Vec<WorkerTimes>,
+}
+
+// busy + idle is the time from the workers starting to the last one finishing, the same for every
+// worker. Idle counts both waiting on the reader for a chunk and sitting done while other workers
+// are still on their last chunks
+#[derive(Debug, Clone, Default)]
+pub struct WorkerTimes {
+    pub busy: Duration,
+    pub idle: Duration,
 }
 
+impl WorkerTimes {
+    pub fn idle_ratio(&self) -> f64 {
+        let total = (self.busy + self.idle).as_secs_f64();
+        if total == 0.0 {
+            return 0.0;
+        }
+        return self.idle.as_secs_f64() / total;
+    }
+}
 
 pub fn run(measurements_path: &str) -> String {
+    let (result, _metrics) = run_with_metrics(measurements_path);
+    return result;
+}
+
+pub fn run_with_metrics(measurements_path: &str) -> (String, PipelineMetrics) {
     const NUM_WORKERS: usize = 4;
     const NUM_BUFS: usize = 8;
     const BUF_SIZE: usize = 16 * 1024 * 1024;
@@ -145,6 +191,7 @@ pub fn run(measurements_path: &str) -> String {
         reader_thread(measurements_file, reader_empty_bufs, reader_full_bufs)
     });
 
+    let workers_start = Instant::now();
     let workers: Vec<_> = (0..NUM_WORKERS)
         .map(|_| { 
             let worker_empty_bufs = empty_bufs.clone();
@@ -155,26 +202,38 @@ pub fn run(measurements_path: &str) -> String {
         })
         .collect();
 
-    let maps: Vec<_> = workers
+    let results: Vec<_> = workers
         .into_iter()
         .map( |h| 
             h.join().unwrap()
         )
         .collect();
+
+    let last_finish = results.iter().map(|(_, _, finished)| *finished).max().unwrap();
+    let worker_times = results
+        .iter()
+        .map(|(_, waiting, finished)| {
+            let idle = *waiting + (last_finish - *finished);
+            WorkerTimes { busy: (last_finish - workers_start).saturating_sub(idle), idle }
+        })
+        .collect();
+    let maps: Vec<_> = results.into_iter().map(|(map, _, _)| map).collect();
     
+    // merged by name: with probing a station's slot depends on which names reached the table
+    // first, so it can differ between workers
     let mut merged_map = CustomHashMap::new();
-    for i in 0..merged_map.backing.len() {
-        if maps[0].backing[i].count == 0 {
-            continue;
-        }
-        let accum = &mut merged_map.backing[i];
-        for j in 0..NUM_WORKERS {
-            let other = &maps[j].backing[i];
-            accum.merge_with(other);
+    for map in &maps {
+        for data in map.backing.iter().filter(|data| data.count > 0) {
+            merged_map.get_mut(data.name.as_deref().unwrap()).merge_with(data);
         }
     }
 
-    return format_output(&merged_map);
+    let metrics = PipelineMetrics {
+        max_full_chunks_depth: full_chunks.max_len.load(Ordering::Relaxed),
+        workers: worker_times,
+    };
+
+    return (format_output(&merged_map), metrics);
 }
 
 #[inline(always)]
@@ -240,11 +299,14 @@ fn format_output(map: &CustomHashMap) -> String {
 
 
 
+// min_temp/max_temp start at i32::MAX/i32::MIN as sentinels, so a slot is only considered
+// occupied once count > 0. A reading of exactly 0.0 still goes through add_temp like any other
+// (count += 1, total += 0), so a station that only ever reads 0.0 reports 0.0/0.0/0.0.
 #[derive(Debug, Clone)]
 struct StationData {
     min_temp: i32,
     max_temp: i32,
-    total: i32,
+    total: i64,
     count: u32,
     name: Option<Vec<u8>>,
 }
@@ -264,7 +326,7 @@ impl StationData {
     pub fn add_temp(&mut self, temp: i32, name: &[u8]) {
         self.min_temp = self.min_temp.min(temp);
         self.max_temp = self.max_temp.max(temp);
-        self.total += temp;
+        self.total += temp as i64;
         self.count += 1;
         if self.name.is_none() {
             self.name = Some(name.to_vec());
@@ -272,6 +334,7 @@ impl StationData {
     }
     #[inline(always)]
     pub fn merge_with(&mut self, other: &StationData) {
+        // an empty other still has the sentinel min/max, which min()/max() ignore
         self.min_temp = self.min_temp.min(other.min_temp);
         self.max_temp = self.max_temp.max(other.max_temp);
         self.total += other.total;
@@ -281,11 +344,11 @@ impl StationData {
         }
     }
     pub fn format_data_point(&self) -> String {
-        return format!("{}={:.1}/{:.1}/{:.1}", 
+        return format!("{}={}/{}/{}", 
             String::from_utf8(self.name.clone().unwrap()).unwrap(), 
-            0.1 * self.min_temp as f32, 
-            0.1 * self.total as f32 / self.count as f32, 
-            0.1 * self.max_temp as f32
+            format_fixed(self.min_temp as i64, 1), 
+            format_fixed(mean_half_up(self.total, self.count), 1), 
+            format_fixed(self.max_temp as i64, 1)
         );
     }
 }
@@ -304,7 +367,13 @@ impl CustomHashMap {
     pub fn get_mut(&mut self, key: &[u8]) -> &mut StationData {
         let u64_key = get_u64_key(key);
         let hashed_key = mix64(u64_key);
-        let index = hashed_key as usize & (32_768 - 1);
+        let mut index = hashed_key as usize & (32_768 - 1);
+        let mut chain = 0;
+        while let Some(name) = &self.backing[index].name && name.as_slice() != key {
+            chain += 1;
+            assert!(chain < self.backing.len(), "hash table full, more than {} distinct station names", self.backing.len());
+            index = (index + 1) & (32_768 - 1);
+        }
         return &mut self.backing[index];
     }
 }
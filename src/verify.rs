@@ -0,0 +1,139 @@
+// Checks on formatted results that don't need the reference output.
+
+use std::collections::HashSet;
+
+use crate::v15::mix64;
+
+// parses one "name=min/mean/max" entry into the name and the three values in tenths
+fn parse_entry(entry: &str) -> Option<(&str, [i64; 3])> {
+    let (name, values) = entry.rsplit_once('=')?;
+    let mut parsed = [0i64; 3];
+    let mut parts = values.split('/');
+    for value in parsed.iter_mut() {
+        *value = parse_one_decimal(parts.next()?)?;
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+    return Some((name, parsed));
+}
+
+// parses "-12.3" style values (exactly one fractional digit) into tenths
+fn parse_one_decimal(value: &str) -> Option<i64> {
+    let (sign, digits) = match value.strip_prefix('-') {
+        Some(rest) => (-1, rest),
+        None => (1, value),
+    };
+    let (int_part, frac_part) = digits.split_once('.')?;
+    if int_part.is_empty() || frac_part.len() != 1 {
+        return None;
+    }
+    if !int_part.bytes().chain(frac_part.bytes()).all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let int_value: i64 = int_part.parse().ok()?;
+    let frac_value: i64 = frac_part.parse().ok()?;
+    return Some(sign * (int_value * 10 + frac_value));
+}
+
+// splits the body of the output into entries. Station names may themselves contain ", ", so a
+// piece that doesn't end in a valid "=min/mean/max" is glued onto the next one
+fn split_entries(body: &str) -> Result<Vec<(&str, [i64; 3])>, String> {
+    let mut entries = vec![];
+    let mut entry_start = 0;
+    let mut search_from = 0;
+    loop {
+        let entry_end = body[search_from..].find(", ").map_or(body.len(), |i| search_from + i);
+        let candidate = &body[entry_start..entry_end];
+        if let Some(entry) = parse_entry(candidate) {
+            entries.push(entry);
+            if entry_end == body.len() {
+                break;
+            }
+            entry_start = entry_end + 2;
+            search_from = entry_start;
+        } else if entry_end == body.len() {
+            return Err(format!("malformed entry \"{}\"", candidate));
+        } else {
+            search_from = entry_end + 2;
+        }
+    }
+    return Ok(entries);
+}
+
+// checks that output follows the spec's format: enclosed in {}, ", " separated entries of
+// name=min/mean/max with one decimal each, min <= mean <= max, and names in sorted order
+pub fn check_output_wellformed(output: &str) -> Result<(), String> {
+    let body = output
+        .strip_prefix('{')
+        .and_then(|rest| rest.strip_suffix('}'))
+        .ok_or("output is not enclosed in {}")?;
+
+    if body.is_empty() {
+        return Ok(());
+    }
+
+    let entries = split_entries(body)?;
+
+    for (name, [min, mean, max]) in &entries {
+        if name.is_empty() {
+            return Err("entry with an empty station name".to_owned());
+        }
+        if !(min <= mean && mean <= max) {
+            return Err(format!("station \"{}\" does not satisfy min <= mean <= max", name));
+        }
+    }
+
+    for pair in entries.windows(2) {
+        let (prev, curr) = (pair[0].0, pair[1].0);
+        if prev.as_bytes() >= curr.as_bytes() {
+            return Err(format!("stations \"{}\" and \"{}\" are not in sorted order", prev, curr));
+        }
+    }
+
+    return Ok(());
+}
+
+// station names of a formatted result, panics if the output isn't well formed enough to split
+fn station_names(output: &str) -> Vec<&str> {
+    let body = output
+        .strip_prefix('{')
+        .and_then(|rest| rest.strip_suffix('}'))
+        .unwrap_or_else(|| panic!("output is not enclosed in {{}}"));
+    if body.is_empty() {
+        return vec![];
+    }
+    let entries = split_entries(body).unwrap_or_else(|e| panic!("{}", e));
+    return entries.into_iter().map(|(name, _)| name).collect();
+}
+
+// returns (stations only in a, stations only in b), useful for finding out which stations a
+// dropped line took with it when an output doesn't match the reference
+pub fn diff_stations(a: &str, b: &str) -> (Vec<String>, Vec<String>) {
+    let a_names: HashSet<_> = station_names(a).into_iter().collect();
+    let b_names: HashSet<_> = station_names(b).into_iter().collect();
+
+    let mut only_in_a: Vec<_> = a_names.difference(&b_names).map(|name| name.to_string()).collect();
+    let mut only_in_b: Vec<_> = b_names.difference(&a_names).map(|name| name.to_string()).collect();
+    only_in_a.sort();
+    only_in_b.sort();
+
+    return (only_in_a, only_in_b);
+}
+
+// a cheap fingerprint of an output for comparing runs (in CI, across machines) without keeping or
+// diffing the full text. The bytes go through v15's mix64 eight at a time, the length is mixed in
+// last so outputs that only differ by trailing zero bytes can't collide. Not cryptographic, only
+// meant to catch accidental differences
+pub fn output_checksum(output: &str) -> u64 {
+    let bytes = output.as_bytes();
+    let mut hash = 0x9e3779b97f4a7c15;
+    let mut chunks = bytes.chunks_exact(8);
+    for chunk in chunks.by_ref() {
+        hash = mix64(hash ^ u64::from_le_bytes(chunk.try_into().unwrap()));
+    }
+    let mut last = [0u8; 8];
+    last[..chunks.remainder().len()].copy_from_slice(chunks.remainder());
+    hash = mix64(hash ^ u64::from_le_bytes(last));
+    return mix64(hash ^ bytes.len() as u64);
+}
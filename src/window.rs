@@ -0,0 +1,67 @@
+// Rolling window statistics: next to the lifetime min/mean/max, every station also reports the
+// min/mean/max of only its last K readings, for recency analysis on time ordered data.
+//
+// "Last K" only means something if lines are seen in file order, and two windows can't be merged
+// without knowing which readings came later, so this mode is single threaded and scans the file
+// front to back. The window is a fixed size ring buffer per station, kept next to StationData
+// rather than inside it so the hash table slots of the normal modes don't grow.
+
+use std::{collections::HashMap, io::{Error, ErrorKind}, ops::ControlFlow};
+
+use crate::v15::{BrcError, StationData, format_fixed, mean_half_up, open_measurements, parse_temp, scan_segment_with};
+
+struct WindowedStation {
+    lifetime: StationData,
+    // the last k temps, ring[next] is the oldest once the ring is full
+    ring: Vec<i32>,
+    next: usize,
+}
+
+impl WindowedStation {
+    fn new(k: usize) -> Self {
+        Self { lifetime: StationData::new(), ring: Vec::with_capacity(k), next: 0 }
+    }
+    fn add_temp(&mut self, temp: i32, name: &[u8], k: usize) {
+        self.lifetime.add_temp(temp, name);
+        if self.ring.len() < k {
+            self.ring.push(temp);
+        } else {
+            self.ring[self.next] = temp;
+            self.next = (self.next + 1) % k;
+        }
+    }
+    fn format_window(&self) -> String {
+        let min = *self.ring.iter().min().unwrap();
+        let max = *self.ring.iter().max().unwrap();
+        let total: i64 = self.ring.iter().map(|temp| *temp as i64).sum();
+        let mean = mean_half_up(total, self.ring.len() as u32);
+        return format!("{}/{}/{}", format_fixed(min as i64, 1), format_fixed(mean, 1), format_fixed(max as i64, 1));
+    }
+}
+
+// output entries look like "Hamburg=-1.0/4.5/12.0 last3=2.0/5.0/8.0"
+pub fn run_windowed(measurements_path: &str, k: usize) -> Result<String, BrcError> {
+    if k == 0 {
+        return Err(BrcError::Io(Error::new(ErrorKind::InvalidInput, "window size has to be at least 1")));
+    }
+    let file = open_measurements(measurements_path)?;
+    let file_len = file.metadata()?.len() as usize;
+
+    let mut stations: HashMap<Vec<u8>, WindowedStation> = HashMap::new();
+    let _ = scan_segment_with(&file, 0, file_len, None, |name_slice, temp_slice| {
+        let temp = parse_temp(temp_slice);
+        match stations.get_mut(name_slice) {
+            Some(station) => station.add_temp(temp, name_slice, k),
+            None => stations.entry(name_slice.to_vec()).or_insert_with(|| WindowedStation::new(k)).add_temp(temp, name_slice, k),
+        }
+        ControlFlow::Continue(())
+    });
+
+    let mut parts = stations
+        .values()
+        .map(|station| format!("{} last{}={}", station.lifetime.format_data_point(), k, station.format_window()))
+        .collect::<Vec<_>>();
+    parts.sort();
+
+    return Ok("{".to_owned() + &parts.join(", ") + "}");
+}